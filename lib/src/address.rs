@@ -0,0 +1,359 @@
+use crate::dsn::{Notify, OriginalRecipient};
+use crate::message_parser::MessageParser;
+use crate::reply::Reply;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// An address as used in `MAIL FROM` and `RCPT TO`, e.g. `user@example.com`
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Mailbox {
+    /// The part before the `@`, with quotes and escapes removed
+    pub local: String,
+    /// The domain name, or an address literal like `[127.0.0.1]`. This is only empty for
+    /// `RCPT TO:<Postmaster>`.
+    pub domain: String,
+}
+
+/// A recipient of `RCPT TO`, with the DSN parameters of RFC 3461 that were sent for it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recipient {
+    pub mailbox: Mailbox,
+    /// `NOTIFY=`, or `None` if the client left it to the server
+    pub notify: Option<Notify>,
+    /// `ORCPT=`
+    pub original_recipient: Option<OriginalRecipient>,
+}
+
+impl Mailbox {
+    /// Returns false for internationalized addresses, which can only be used with SMTPUTF8
+    pub fn is_ascii(&self) -> bool {
+        self.local.is_ascii() && self.domain.is_ascii()
+    }
+}
+
+impl fmt::Display for Mailbox {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if is_dot_string(&self.local) {
+            write!(fmt, "{}", self.local)?;
+        } else {
+            write!(fmt, "\"")?;
+            for c in self.local.chars() {
+                if c == '"' || c == '\\' {
+                    write!(fmt, "\\")?;
+                }
+                write!(fmt, "{}", c)?;
+            }
+            write!(fmt, "\"")?;
+        }
+        if !self.domain.is_empty() {
+            write!(fmt, "@{}", self.domain)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PathError {
+    /// The command itself is malformed
+    Syntax(&'static str),
+    /// The mailbox between the `<>` is malformed
+    Mailbox(&'static str),
+}
+
+impl PathError {
+    /// `bad_mailbox` is the enhanced status code for a malformed mailbox, which differs between
+    /// the sender (5.1.7) and the recipients (5.1.3)
+    pub fn reply(&self, bad_mailbox: (u8, u16, u16)) -> Reply {
+        match self {
+            PathError::Syntax(reason) => Reply::new(501, (5, 5, 4), *reason),
+            PathError::Mailbox(reason) => Reply::new(553, bad_mailbox, *reason),
+        }
+    }
+}
+
+/// Parses `"<" [ A-d-l ":" ] Mailbox ">"` or `"<>"`, as defined in RFC 5321 section 4.1.2.
+/// Anything after the path, like ESMTP parameters, is left in the parser.
+///
+/// UTF-8 is accepted as in RFC 6531 section 3.3. It is up to the caller to reject it if the
+/// client did not request SMTPUTF8.
+pub(crate) fn parse_path(parser: &mut MessageParser) -> Result<Option<Mailbox>, PathError> {
+    if !parser.consume(b'<') {
+        return Err(PathError::Syntax(
+            "Expected the address to be enclosed in <>",
+        ));
+    }
+    if parser.consume(b'>') {
+        return Ok(None);
+    }
+    // RFC 5321 section 3.3: source routes have to be accepted, but should be ignored
+    if parser.peek() == Some(b'@') {
+        let route = parser.consume_while(|b| b != b':' && b != b'>');
+        if !parser.consume(b':') || !is_source_route(route) {
+            return Err(PathError::Mailbox("Malformed source route"));
+        }
+    }
+
+    let local = parse_local_part(parser)?;
+    let domain = if parser.consume(b'@') {
+        parse_domain(parser)?
+    } else if local.eq_ignore_ascii_case("postmaster") {
+        // RFC 5321 section 4.1.1.3: "Postmaster" without a domain has to be supported
+        String::new()
+    } else {
+        return Err(PathError::Mailbox("Expected an @ in the address"));
+    };
+
+    if !parser.consume(b'>') {
+        return Err(PathError::Syntax("Expected the address to end with >"));
+    }
+    match parser.peek() {
+        None | Some(b' ') => Ok(Some(Mailbox { local, domain })),
+        Some(_) => Err(PathError::Syntax("Expected a space after the address")),
+    }
+}
+
+fn parse_local_part(parser: &mut MessageParser) -> Result<String, PathError> {
+    if !parser.consume(b'"') {
+        let local = parser.consume_while(|b| is_atext(b) || b == b'.');
+        return if is_dot_string(local) {
+            Ok(local.to_owned())
+        } else {
+            Err(PathError::Mailbox("Invalid local part"))
+        };
+    }
+
+    let mut local = String::new();
+    loop {
+        match parser.next_char() {
+            Some('"') => return Ok(local),
+            Some('\\') => match parser.next_char() {
+                Some(c) if is_qtext(c) => local.push(c),
+                _ => return Err(PathError::Mailbox("Invalid escape in quoted local part")),
+            },
+            Some(c) if is_qtext(c) => local.push(c),
+            Some(_) => return Err(PathError::Mailbox("Invalid character in quoted local part")),
+            None => return Err(PathError::Mailbox("Unterminated quoted local part")),
+        }
+    }
+}
+
+/// Parses `Domain / address-literal`. Address literals keep their brackets.
+pub(crate) fn parse_domain(parser: &mut MessageParser) -> Result<String, PathError> {
+    if parser.consume(b'[') {
+        let literal = parser.consume_while(|b| b != b']');
+        if !parser.consume(b']') {
+            return Err(PathError::Mailbox("Unterminated address literal"));
+        }
+        // The literal can contain UTF-8, so it is not sliced at a fixed byte offset
+        let is_ipv6 = literal
+            .get(..5)
+            .map_or(false, |prefix| prefix.eq_ignore_ascii_case("IPv6:"));
+        let is_valid = match literal.get(5..) {
+            Some(ipv6) if is_ipv6 => ipv6.parse::<Ipv6Addr>().is_ok(),
+            _ => literal.parse::<Ipv4Addr>().is_ok(),
+        };
+        return if is_valid {
+            Ok(format!("[{}]", literal))
+        } else {
+            Err(PathError::Mailbox("Invalid address literal"))
+        };
+    }
+
+    let domain = parser.consume_while(|b| is_label_byte(b) || b == b'.');
+    if is_domain(domain) {
+        Ok(domain.to_owned())
+    } else {
+        Err(PathError::Mailbox("Invalid domain"))
+    }
+}
+
+/// `atext` as defined in RFC 5322 section 3.2.3, extended with UTF-8 by RFC 6531
+fn is_atext(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-/=?^_`{|}~".contains(&b) || !b.is_ascii()
+}
+
+fn is_qtext(c: char) -> bool {
+    c == ' ' || c.is_ascii_graphic() || !c.is_ascii()
+}
+
+/// Letters, digits and hyphens, or the bytes of a UTF-8 U-label
+fn is_label_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'-' || !b.is_ascii()
+}
+
+fn is_dot_string(s: &str) -> bool {
+    !s.is_empty()
+        && s.split('.')
+            .all(|atom| !atom.is_empty() && atom.bytes().all(is_atext))
+}
+
+fn is_domain(s: &str) -> bool {
+    !s.is_empty()
+        && s.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(is_label_byte)
+        })
+}
+
+/// Checks `"@" Domain *( "," "@" Domain )`
+fn is_source_route(route: &str) -> bool {
+    route
+        .split(',')
+        .all(|hop| hop.starts_with('@') && is_domain(&hop[1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(path: &str) -> Result<Option<Mailbox>, PathError> {
+        parse_path(&mut MessageParser::new(path))
+    }
+
+    fn mailbox(local: &str, domain: &str) -> Option<Mailbox> {
+        Some(Mailbox {
+            local: local.to_owned(),
+            domain: domain.to_owned(),
+        })
+    }
+
+    #[test]
+    fn null_path() {
+        assert_eq!(parse("<>"), Ok(None));
+    }
+
+    #[test]
+    fn simple_address() {
+        assert_eq!(
+            parse("<first.last@example.com>"),
+            Ok(mailbox("first.last", "example.com"))
+        );
+    }
+
+    #[test]
+    fn parameters_are_left_in_the_parser() {
+        let mut parser = MessageParser::new("<user@example.com> SIZE=100");
+        assert_eq!(parse_path(&mut parser), Ok(mailbox("user", "example.com")));
+        assert_eq!(parser.rest(), " SIZE=100");
+    }
+
+    #[test]
+    fn quoted_local_part() {
+        assert_eq!(
+            parse(r#"<"john \"doe\""@example.com>"#),
+            Ok(mailbox("john \"doe\"", "example.com"))
+        );
+        assert_eq!(
+            parse(r#"<"unterminated@example.com>"#),
+            Err(PathError::Mailbox("Unterminated quoted local part"))
+        );
+    }
+
+    #[test]
+    fn source_route_is_ignored() {
+        assert_eq!(
+            parse("<@relay.example,@other.example:user@example.com>"),
+            Ok(mailbox("user", "example.com"))
+        );
+        assert_eq!(
+            parse("<@relay..example:user@example.com>"),
+            Err(PathError::Mailbox("Malformed source route"))
+        );
+    }
+
+    #[test]
+    fn postmaster_without_domain() {
+        assert_eq!(parse("<Postmaster>"), Ok(mailbox("Postmaster", "")));
+        assert_eq!(
+            parse("<user>"),
+            Err(PathError::Mailbox("Expected an @ in the address"))
+        );
+    }
+
+    #[test]
+    fn address_literals() {
+        assert_eq!(
+            parse("<user@[192.0.2.1]>"),
+            Ok(mailbox("user", "[192.0.2.1]"))
+        );
+        assert_eq!(
+            parse("<user@[IPv6:2001:db8::1]>"),
+            Ok(mailbox("user", "[IPv6:2001:db8::1]"))
+        );
+        assert_eq!(
+            parse("<user@[192.0.2.256]>"),
+            Err(PathError::Mailbox("Invalid address literal"))
+        );
+        // Multi-byte characters must not make the IPv6 prefix check panic
+        assert_eq!(
+            parse("<user@[1234é]>"),
+            Err(PathError::Mailbox("Invalid address literal"))
+        );
+        assert_eq!(
+            parse("<user@[IPv6é:1]>"),
+            Err(PathError::Mailbox("Invalid address literal"))
+        );
+    }
+
+    #[test]
+    fn internationalized_address() {
+        let parsed = parse("<用户@例子.广告>").unwrap().unwrap();
+        assert_eq!(
+            parsed,
+            Mailbox {
+                local: "用户".to_owned(),
+                domain: "例子.广告".to_owned(),
+            }
+        );
+        assert!(!parsed.is_ascii());
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(
+            parse("user@example.com"),
+            Err(PathError::Syntax(
+                "Expected the address to be enclosed in <>"
+            ))
+        );
+        assert_eq!(
+            parse("<user@example.com"),
+            Err(PathError::Syntax("Expected the address to end with >"))
+        );
+        assert_eq!(
+            parse("<user@example.com>SIZE=100"),
+            Err(PathError::Syntax("Expected a space after the address"))
+        );
+    }
+
+    #[test]
+    fn malformed_mailboxes() {
+        assert_eq!(
+            parse("<.user@example.com>"),
+            Err(PathError::Mailbox("Invalid local part"))
+        );
+        assert_eq!(
+            parse("<user@-example.com>"),
+            Err(PathError::Mailbox("Invalid domain"))
+        );
+        assert_eq!(
+            parse("<user@example..com>"),
+            Err(PathError::Mailbox("Invalid domain"))
+        );
+    }
+
+    #[test]
+    fn display_quotes_the_local_part_when_needed() {
+        let quoted = Mailbox {
+            local: "john \"doe\"".to_owned(),
+            domain: "example.com".to_owned(),
+        };
+        assert_eq!(quoted.to_string(), r#""john \"doe\""@example.com"#);
+        assert_eq!(
+            mailbox("user", "example.com").unwrap().to_string(),
+            "user@example.com"
+        );
+    }
+}
@@ -0,0 +1,667 @@
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum AuthMechanism {
+    Plain,
+    Login,
+    CramMd5,
+    ScramSha256,
+}
+
+impl AuthMechanism {
+    pub fn name(self) -> &'static str {
+        match self {
+            AuthMechanism::Plain => "PLAIN",
+            AuthMechanism::Login => "LOGIN",
+            AuthMechanism::CramMd5 => "CRAM-MD5",
+            AuthMechanism::ScramSha256 => "SCRAM-SHA-256",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<AuthMechanism> {
+        [
+            AuthMechanism::Plain,
+            AuthMechanism::Login,
+            AuthMechanism::CramMd5,
+            AuthMechanism::ScramSha256,
+        ]
+        .iter()
+        .cloned()
+        .find(|m| m.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Returns true if the client sends the password itself, instead of proving it knows it
+    pub fn sends_password(self) -> bool {
+        match self {
+            AuthMechanism::Plain | AuthMechanism::Login => true,
+            AuthMechanism::CramMd5 | AuthMechanism::ScramSha256 => false,
+        }
+    }
+}
+
+/// The credentials a client sent with `AUTH`.
+#[derive(Clone)]
+pub struct Credentials {
+    /// The identity the client wants to act as, if it is different from `username`
+    pub authorization_id: Option<String>,
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Credentials")
+            .field("authorization_id", &self.authorization_id)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// What the server needs to know about a user to verify a challenge-response mechanism.
+pub enum StoredCredentials {
+    /// The shared secret, required for `CRAM-MD5`
+    Password(String),
+    /// The salted keys, required for `SCRAM-SHA-256`
+    Scram(ScramCredentials),
+}
+
+/// The keys of a user as defined in RFC 5802 section 3. These should be generated once with
+/// `ScramCredentials::sha256_from_password` and stored instead of the password.
+#[derive(Clone)]
+pub struct ScramCredentials {
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+lazy_static::lazy_static! {
+    /// Derives the salts of unknown users, so every attempt for a user gets the same salt
+    static ref UNKNOWN_USER_KEY: [u8; 32] = rand::random();
+}
+
+impl ScramCredentials {
+    /// Derive the SCRAM-SHA-256 keys of a password. RFC 7677 recommends at least 4096
+    /// iterations.
+    pub fn sha256_from_password(password: &str, salt: &[u8], iterations: u32) -> Self {
+        let mut salted_password = [0u8; 32];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(
+            password.as_bytes(),
+            salt,
+            iterations as usize,
+            &mut salted_password,
+        );
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        ScramCredentials {
+            salt: salt.to_vec(),
+            iterations,
+            stored_key: Sha256::digest(&client_key).to_vec(),
+            server_key: hmac_sha256(&salted_password, b"Server Key"),
+        }
+    }
+
+    /// Keys that no proof matches, with a salt that looks like a stored one
+    fn unknown_user(username: &str) -> Self {
+        ScramCredentials {
+            salt: hmac_sha256(&*UNKNOWN_USER_KEY, username.as_bytes())[..16].to_vec(),
+            iterations: 4096,
+            stored_key: rand::random::<[u8; 32]>().to_vec(),
+            server_key: rand::random::<[u8; 32]>().to_vec(),
+        }
+    }
+}
+
+impl std::fmt::Debug for ScramCredentials {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("ScramCredentials")
+            .field("salt", &self.salt)
+            .field("iterations", &self.iterations)
+            .field("stored_key", &"<redacted>")
+            .field("server_key", &"<redacted>")
+            .finish()
+    }
+}
+
+/// A SASL exchange that is waiting for the next response of the client.
+#[derive(Debug)]
+pub(crate) enum Exchange {
+    Plain,
+    LoginUsername,
+    LoginPassword {
+        username: String,
+    },
+    CramMd5 {
+        challenge: String,
+    },
+    ScramClientFirst,
+    ScramClientFinal {
+        username: String,
+        gs2_header: String,
+        nonce: String,
+        auth_message: String,
+        credentials: ScramCredentials,
+    },
+    ScramServerFinal {
+        username: String,
+    },
+}
+
+/// A SASL exchange that is waiting for the stored credentials of a user.
+#[derive(Debug)]
+pub(crate) enum Lookup {
+    CramMd5 {
+        username: String,
+        challenge: String,
+        digest: String,
+    },
+    Scram {
+        username: String,
+        gs2_header: String,
+        client_first_bare: String,
+        client_nonce: String,
+    },
+}
+
+#[derive(Debug)]
+pub(crate) enum Step {
+    /// Send the (not yet base64 encoded) challenge to the client, and wait for the response
+    Challenge(Exchange, Vec<u8>),
+    /// Let the handler verify the credentials
+    Done(Credentials),
+    /// Ask the handler for the stored credentials, and continue with `Lookup::finish`
+    Lookup(Lookup),
+    Authenticated(String),
+    Rejected(String),
+    Cancelled,
+    Failed(&'static str),
+}
+
+impl Exchange {
+    pub fn start(mechanism: AuthMechanism, initial_response: Option<&str>, host: &str) -> Step {
+        let exchange = match mechanism {
+            AuthMechanism::Plain => Exchange::Plain,
+            AuthMechanism::Login => Exchange::LoginUsername,
+            AuthMechanism::CramMd5 => {
+                if initial_response.is_some() {
+                    return Step::Failed("CRAM-MD5 does not allow an initial response");
+                }
+                let challenge = format!(
+                    "<{}.{}@{}>",
+                    rand::random::<u64>(),
+                    std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0),
+                    host
+                );
+                Exchange::CramMd5 { challenge }
+            }
+            AuthMechanism::ScramSha256 => Exchange::ScramClientFirst,
+        };
+        match initial_response {
+            Some(response) => exchange.respond(response),
+            None => exchange.challenge(),
+        }
+    }
+
+    fn challenge(self) -> Step {
+        let challenge = match &self {
+            Exchange::Plain | Exchange::ScramClientFirst => Vec::new(),
+            Exchange::LoginUsername => b"Username:".to_vec(),
+            Exchange::LoginPassword { .. } => b"Password:".to_vec(),
+            Exchange::CramMd5 { challenge } => challenge.as_bytes().to_vec(),
+            Exchange::ScramClientFinal { .. } | Exchange::ScramServerFinal { .. } => {
+                unreachable!("SCRAM challenges are created when the previous response is received")
+            }
+        };
+        Step::Challenge(self, challenge)
+    }
+
+    pub fn respond(self, response: &str) -> Step {
+        if response == "*" {
+            return Step::Cancelled;
+        }
+        let data = match decode(response) {
+            Ok(data) => data,
+            Err(e) => return Step::Failed(e),
+        };
+        let result = match self {
+            Exchange::Plain => decode_plain(&data).map(Step::Done),
+            Exchange::LoginUsername => {
+                to_string(data).map(|username| Exchange::LoginPassword { username }.challenge())
+            }
+            Exchange::LoginPassword { username } => to_string(data).map(|password| {
+                Step::Done(Credentials {
+                    authorization_id: None,
+                    username,
+                    password,
+                })
+            }),
+            Exchange::CramMd5 { challenge } => {
+                to_string(data).and_then(|response| cram_md5_response(challenge, &response))
+            }
+            Exchange::ScramClientFirst => {
+                to_string(data).and_then(|response| scram_client_first(&response))
+            }
+            Exchange::ScramClientFinal {
+                username,
+                gs2_header,
+                nonce,
+                auth_message,
+                credentials,
+            } => to_string(data).and_then(|response| {
+                scram_client_final(
+                    &response,
+                    username,
+                    &gs2_header,
+                    &nonce,
+                    auth_message,
+                    &credentials,
+                )
+            }),
+            // The client has to send an empty response to the server signature
+            Exchange::ScramServerFinal { username } => Ok(Step::Authenticated(username)),
+        };
+        result.unwrap_or_else(Step::Failed)
+    }
+}
+
+impl Lookup {
+    pub fn mechanism(&self) -> AuthMechanism {
+        match self {
+            Lookup::CramMd5 { .. } => AuthMechanism::CramMd5,
+            Lookup::Scram { .. } => AuthMechanism::ScramSha256,
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        match self {
+            Lookup::CramMd5 { username, .. } | Lookup::Scram { username, .. } => username,
+        }
+    }
+
+    pub fn finish(self, credentials: Option<StoredCredentials>) -> Step {
+        self.finish_with_nonce(credentials, || base64::encode(&rand::random::<[u8; 18]>()))
+    }
+
+    /// `server_nonce` creates the part of the SCRAM nonce that the server adds
+    fn finish_with_nonce(
+        self,
+        credentials: Option<StoredCredentials>,
+        server_nonce: impl FnOnce() -> String,
+    ) -> Step {
+        match (self, credentials) {
+            (
+                Lookup::CramMd5 {
+                    username,
+                    challenge,
+                    digest,
+                },
+                Some(StoredCredentials::Password(password)),
+            ) => {
+                let mut mac = Hmac::<Md5>::new_varkey(password.as_bytes())
+                    .expect("HMAC accepts all key sizes");
+                mac.input(challenge.as_bytes());
+                let expected = to_hex(&mac.result().code());
+                if constant_time_eq(expected.as_bytes(), digest.to_ascii_lowercase().as_bytes()) {
+                    Step::Authenticated(username)
+                } else {
+                    Step::Rejected(username)
+                }
+            }
+            (
+                Lookup::Scram {
+                    username,
+                    gs2_header,
+                    client_first_bare,
+                    client_nonce,
+                },
+                credentials,
+            ) => {
+                let credentials = match credentials {
+                    Some(StoredCredentials::Scram(credentials)) => credentials,
+                    other => {
+                        if other.is_some() {
+                            log::warn!(
+                                "The stored credentials of {:?} can not be used for {}",
+                                username,
+                                AuthMechanism::ScramSha256.name()
+                            );
+                        }
+                        // RFC 5802 section 5.1: unknown users get a challenge as well, so the
+                        // client can not tell which users exist. The proof is rejected later.
+                        ScramCredentials::unknown_user(&username)
+                    }
+                };
+                let nonce = format!("{}{}", client_nonce, server_nonce());
+                let server_first = format!(
+                    "r={},s={},i={}",
+                    nonce,
+                    base64::encode(&credentials.salt),
+                    credentials.iterations
+                );
+                let auth_message = format!("{},{}", client_first_bare, server_first);
+                Step::Challenge(
+                    Exchange::ScramClientFinal {
+                        username,
+                        gs2_header,
+                        nonce,
+                        auth_message,
+                        credentials,
+                    },
+                    server_first.into_bytes(),
+                )
+            }
+            (lookup, Some(_)) => {
+                log::warn!(
+                    "The stored credentials of {:?} can not be used for {}",
+                    lookup.username(),
+                    lookup.mechanism().name()
+                );
+                Step::Rejected(lookup.username().to_owned())
+            }
+            (lookup, None) => Step::Rejected(lookup.username().to_owned()),
+        }
+    }
+}
+
+fn decode(response: &str) -> Result<Vec<u8>, &'static str> {
+    // RFC 4954 section 4: a single "=" is an empty response
+    if response == "=" {
+        return Ok(Vec::new());
+    }
+    base64::decode(response).map_err(|_| "Could not decode the base64 response")
+}
+
+fn to_string(data: Vec<u8>) -> Result<String, &'static str> {
+    String::from_utf8(data).map_err(|_| "Credentials have to be valid UTF-8")
+}
+
+/// Decodes `[authzid] NUL authcid NUL passwd`, as defined in RFC 4616
+fn decode_plain(data: &[u8]) -> Result<Credentials, &'static str> {
+    let mut parts = data.split(|b| *b == 0);
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(authorization_id), Some(username), Some(password), None) => Ok(Credentials {
+            authorization_id: if authorization_id.is_empty() {
+                None
+            } else {
+                Some(to_string(authorization_id.to_vec())?)
+            },
+            username: to_string(username.to_vec())?,
+            password: to_string(password.to_vec())?,
+        }),
+        _ => Err("Malformed PLAIN response"),
+    }
+}
+
+/// Parses `username SP digest`, as defined in RFC 2195
+fn cram_md5_response(challenge: String, response: &str) -> Result<Step, &'static str> {
+    let index = response.rfind(' ').ok_or("Malformed CRAM-MD5 response")?;
+    Ok(Step::Lookup(Lookup::CramMd5 {
+        username: response[..index].to_owned(),
+        challenge,
+        digest: response[index + 1..].to_owned(),
+    }))
+}
+
+/// Parses `gs2-header client-first-message-bare`, as defined in RFC 5802 section 7
+fn scram_client_first(response: &str) -> Result<Step, &'static str> {
+    let mut parts = response.splitn(3, ',');
+    let (channel_binding, authorization_id, client_first_bare) =
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(c), Some(a), Some(b)) => (c, a, b),
+            _ => return Err("Malformed SCRAM message"),
+        };
+    match channel_binding {
+        "n" | "y" => {}
+        c if c.starts_with("p=") => return Err("Channel binding is not supported"),
+        _ => return Err("Malformed SCRAM message"),
+    }
+    if !authorization_id.is_empty() {
+        return Err("Authorization identities are not supported");
+    }
+
+    let mut username = None;
+    let mut client_nonce = None;
+    for attribute in client_first_bare.split(',') {
+        if attribute.starts_with("m=") {
+            return Err("SCRAM extensions are not supported");
+        } else if attribute.starts_with("n=") {
+            username = Some(decode_saslname(&attribute[2..])?);
+        } else if attribute.starts_with("r=") {
+            client_nonce = Some(attribute[2..].to_owned());
+        }
+    }
+    match (username, client_nonce) {
+        (Some(username), Some(client_nonce)) => Ok(Step::Lookup(Lookup::Scram {
+            username,
+            gs2_header: format!("{},{},", channel_binding, authorization_id),
+            client_first_bare: client_first_bare.to_owned(),
+            client_nonce,
+        })),
+        _ => Err("Malformed SCRAM message"),
+    }
+}
+
+/// Verifies `channel-binding "," nonce ["," extensions] "," proof`, as defined in RFC 5802
+/// section 7
+fn scram_client_final(
+    response: &str,
+    username: String,
+    gs2_header: &str,
+    nonce: &str,
+    auth_message: String,
+    credentials: &ScramCredentials,
+) -> Result<Step, &'static str> {
+    let index = response.rfind(",p=").ok_or("Malformed SCRAM message")?;
+    let without_proof = &response[..index];
+    let proof = base64::decode(&response[index + 3..]).map_err(|_| "Malformed SCRAM proof")?;
+
+    let mut attributes = without_proof.split(',');
+    if attributes.next() != Some(format!("c={}", base64::encode(gs2_header)).as_str()) {
+        return Err("Channel binding does not match");
+    }
+    if attributes.next() != Some(format!("r={}", nonce).as_str()) {
+        return Err("Nonce does not match");
+    }
+
+    let auth_message = format!("{},{}", auth_message, without_proof);
+    let client_signature = hmac_sha256(&credentials.stored_key, auth_message.as_bytes());
+    if proof.len() != client_signature.len() {
+        return Ok(Step::Rejected(username));
+    }
+    let client_key: Vec<u8> = proof
+        .iter()
+        .zip(client_signature.iter())
+        .map(|(a, b)| a ^ b)
+        .collect();
+    if !constant_time_eq(&Sha256::digest(&client_key), &credentials.stored_key) {
+        return Ok(Step::Rejected(username));
+    }
+
+    let server_signature = hmac_sha256(&credentials.server_key, auth_message.as_bytes());
+    Ok(Step::Challenge(
+        Exchange::ScramServerFinal { username },
+        format!("v={}", base64::encode(&server_signature)).into_bytes(),
+    ))
+}
+
+/// Decodes the `=2C` and `=3D` escapes of a SCRAM username
+fn decode_saslname(name: &str) -> Result<String, &'static str> {
+    let mut result = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(index) = rest.find('=') {
+        result += &rest[..index];
+        match rest.get(index..index + 3) {
+            Some("=2C") => result.push(','),
+            Some("=3D") => result.push('='),
+            _ => return Err("Malformed SCRAM username"),
+        }
+        rest = &rest[index + 3..];
+    }
+    result += rest;
+    Ok(result)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts all key sizes");
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn challenge(step: Step) -> (Exchange, String) {
+        match step {
+            Step::Challenge(exchange, challenge) => {
+                (exchange, String::from_utf8(challenge).unwrap())
+            }
+            other => panic!("Expected a challenge, got {:?}", other),
+        }
+    }
+
+    fn lookup(step: Step) -> Lookup {
+        match step {
+            Step::Lookup(lookup) => lookup,
+            other => panic!("Expected a lookup, got {:?}", other),
+        }
+    }
+
+    /// Runs the example of RFC 7677 section 3 up to the client's proof
+    fn scram_example(password: &str) -> Step {
+        let (exchange, server_challenge) = challenge(Exchange::start(
+            AuthMechanism::ScramSha256,
+            None,
+            "localhost",
+        ));
+        assert_eq!(server_challenge, "");
+        let lookup = lookup(exchange.respond(&base64::encode("n,,n=user,r=rOprNGfwEbeRWgbNEkqO")));
+        assert_eq!(lookup.username(), "user");
+
+        let salt = base64::decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let credentials = ScramCredentials::sha256_from_password(password, &salt, 4096);
+        let (exchange, server_first) = challenge(
+            lookup.finish_with_nonce(Some(StoredCredentials::Scram(credentials)), || {
+                "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".to_owned()
+            }),
+        );
+        assert_eq!(
+            server_first,
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+        );
+        exchange.respond(&base64::encode(
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+        ))
+    }
+
+    #[test]
+    fn scram_sha256_rfc7677_example() {
+        let (exchange, server_final) = challenge(scram_example("pencil"));
+        assert_eq!(
+            server_final,
+            "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="
+        );
+        match exchange.respond("=") {
+            Step::Authenticated(username) => assert_eq!(username, "user"),
+            other => panic!("Expected the user to be authenticated, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scram_sha256_wrong_password() {
+        match scram_example("pencils") {
+            Step::Rejected(username) => assert_eq!(username, "user"),
+            other => panic!("Expected the user to be rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scram_sha256_unknown_user_gets_a_challenge() {
+        let server_first = |username: &str| {
+            let (exchange, _) = challenge(Exchange::start(
+                AuthMechanism::ScramSha256,
+                None,
+                "localhost",
+            ));
+            let client_first = format!("n,,n={},r=rOprNGfwEbeRWgbNEkqO", username);
+            let lookup = lookup(exchange.respond(&base64::encode(&client_first)));
+            challenge(lookup.finish(None))
+        };
+        let (exchange, first) = server_first("nobody");
+        let (_, second) = server_first("nobody");
+        let (_, other) = server_first("somebody");
+        let salt = |message: &str| message.split(',').nth(1).unwrap().to_owned();
+        assert!(first.starts_with("r=rOprNGfwEbeRWgbNEkqO"));
+        assert!(first.ends_with(",i=4096"));
+        // The salt of a user does not change between attempts, as it would not for a real user
+        assert_eq!(salt(&first), salt(&second));
+        assert_ne!(salt(&first), salt(&other));
+
+        let nonce = first.split(',').next().unwrap();
+        let client_final = format!("c=biws,{},p={}", nonce, base64::encode(&[0u8; 32]));
+        match exchange.respond(&base64::encode(&client_final)) {
+            Step::Rejected(username) => assert_eq!(username, "nobody"),
+            other => panic!("Expected the user to be rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scram_sha256_unsupported_features() {
+        let (exchange, _) = challenge(Exchange::start(
+            AuthMechanism::ScramSha256,
+            None,
+            "localhost",
+        ));
+        match exchange.respond(&base64::encode("p=tls-unique,,n=user,r=abc")) {
+            Step::Failed(reason) => assert_eq!(reason, "Channel binding is not supported"),
+            other => panic!("Expected a failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scram_usernames_are_unescaped() {
+        assert_eq!(decode_saslname("a=2Cb=3Dc"), Ok("a,b=c".to_owned()));
+        assert_eq!(decode_saslname("a=2"), Err("Malformed SCRAM username"));
+        assert_eq!(decode_saslname("a=41"), Err("Malformed SCRAM username"));
+    }
+
+    #[test]
+    fn cram_md5_rfc2195_example() {
+        let lookup = Lookup::CramMd5 {
+            username: "tim".to_owned(),
+            challenge: "<1896.697170952@postoffice.reston.mci.net>".to_owned(),
+            digest: "b913a602c7eda7a495b4e6e7334d3890".to_owned(),
+        };
+        match lookup.finish(Some(StoredCredentials::Password(
+            "tanstaaftanstaaf".to_owned(),
+        ))) {
+            Step::Authenticated(username) => assert_eq!(username, "tim"),
+            other => panic!("Expected the user to be authenticated, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn plain_credentials() {
+        let credentials = decode_plain(b"admin\0user\0secret").unwrap();
+        assert_eq!(credentials.authorization_id, Some("admin".to_owned()));
+        assert_eq!(credentials.username, "user");
+        assert_eq!(credentials.password, "secret");
+        let credentials = decode_plain(b"\0user\0secret").unwrap();
+        assert_eq!(credentials.authorization_id, None);
+        assert!(decode_plain(b"user\0secret").is_err());
+    }
+}
@@ -1,69 +1,178 @@
+use crate::address::{Mailbox, Recipient};
+use crate::auth::{AuthMechanism, Credentials, StoredCredentials};
+use crate::config::{Config, ListenerRole};
 use crate::connection::State;
+use crate::dsn::DsnReturn;
+use crate::envelope::{Envelope, RcptDecision, SenderDecision};
+use crate::parameters::{BodyType, Parameters};
+use crate::reply::Reply;
+use crate::verdict::{Rejection, Verdict};
 use crate::MailHandlerAsync;
+use failure::{Fail, ResultExt};
 use futures::channel::{mpsc, oneshot};
 use futures::{FutureExt, SinkExt, StreamExt};
+use mailparse::{MailHeaderMap, ParsedMail};
+use std::fs;
+use std::io;
 use std::mem;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Clone)]
 pub struct Collector {
-    sender: mpsc::UnboundedSender<OwnedEmail>,
+    sender: mpsc::UnboundedSender<Request>,
+    /// The number of requests that are waiting for a handler or being handled
+    pending: Arc<AtomicUsize>,
+    max_pending: usize,
+}
+
+/// Returned when `Config::max_pending_requests` requests are already waiting for a handler
+#[derive(Debug, Fail)]
+#[fail(display = "All mail handlers are busy")]
+pub struct Busy;
+
+/// Counts a request as pending until it is dropped
+struct Pending(Arc<AtomicUsize>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+enum Request {
+    Mail(OwnedEmail),
+    Authenticate(Credentials, oneshot::Sender<bool>),
+    LookupCredentials(
+        AuthMechanism,
+        String,
+        oneshot::Sender<Option<StoredCredentials>>,
+    ),
+    ValidateHostname(String, oneshot::Sender<bool>),
+    ValidateSender(Envelope, oneshot::Sender<SenderDecision>),
+    ValidateRecipient(Envelope, Mailbox, oneshot::Sender<RcptDecision>),
 }
 
 struct OwnedEmail {
-    peer_addr: SocketAddr,
-    used_ssl: bool,
-    from: String,
-    to: Vec<String>,
-    body: String,
-    returner: oneshot::Sender<bool>,
+    envelope: Envelope,
+    body: Vec<u8>,
+    returner: oneshot::Sender<Vec<Verdict>>,
 }
 
 #[derive(Debug)]
 pub struct Email<'a> {
     pub peer_addr: SocketAddr,
+    pub role: ListenerRole,
     pub used_ssl: bool,
-    pub from: String,
-    pub to: Vec<String>,
+    /// The name the client introduced itself with in `EHLO` or `HELO`, a domain or an address
+    /// literal like `[127.0.0.1]`
+    pub helo_name: Option<String>,
+    /// The username the client logged in with, if it sent `AUTH`
+    pub authenticated_user: Option<String>,
+    /// The reverse-path of `MAIL FROM`. This is `None` for the null reverse-path `<>`, which
+    /// is used by bounces.
+    pub from: Option<Mailbox>,
+    /// The ESMTP parameters of `MAIL FROM`
+    pub mail_parameters: Parameters,
+    /// The body type the client declared with `BODY=`
+    pub body_type: BodyType,
+    /// True if the client sent `SMTPUTF8`, so the addresses and headers can contain UTF-8
+    pub smtputf8: bool,
+    /// The `ENVID` of RFC 3461, which has to be included in delivery status notifications
+    pub envelope_id: Option<String>,
+    /// The `RET` of RFC 3461, which says how much of the message a bounce should contain
+    pub dsn_return: Option<DsnReturn>,
+    /// The recipients of `RCPT TO`, each with its own DSN parameters
+    pub to: Vec<Recipient>,
     pub body: mailparse::ParsedMail<'a>,
+    /// The message exactly as the client sent it, with the dot-stuffing removed. This can be
+    /// used to archive or forward the message without parsing it again.
+    pub raw_body: &'a [u8],
 }
 
 impl Collector {
-    pub async fn spawn(
-        mut handler: impl MailHandlerAsync + 'static,
-    ) -> (crate::Future<Result<(), failure::Error>>, Collector) {
-        let (sender, mut receiver) = mpsc::unbounded::<OwnedEmail>();
+    /// Every handler handles one request at a time, so as many requests are handled
+    /// concurrently as there are handlers. A `MailHandler` blocks while it handles a request, so
+    /// it runs on its own thread. Other handlers run as tasks on the runtime.
+    pub async fn spawn<H>(
+        handlers: Vec<H>,
+        config: &Config,
+    ) -> Result<(crate::Future<Result<(), failure::Error>>, Collector), failure::Error>
+    where
+        H: MailHandlerAsync + 'static,
+    {
+        if handlers.is_empty() {
+            failure::bail!("At least one mail handler is needed");
+        }
+        let (sender, mut receiver) = mpsc::unbounded::<Request>();
+        // Every handler runs in its own task or thread, which reports back when it is idle
+        let (idle_sender, mut idle_receiver) = mpsc::unbounded::<usize>();
+        let mut workers = Vec::with_capacity(handlers.len());
+        let mut worker_futures = Vec::with_capacity(handlers.len());
+        for (index, mut handler) in handlers.into_iter().enumerate() {
+            let (worker_sender, mut worker_receiver) = mpsc::unbounded::<Request>();
+            let idle_sender = idle_sender.clone();
+            let config = config.clone();
+            let _ = idle_sender.unbounded_send(index);
+            let is_blocking = handler.is_blocking();
+            let worker = async move {
+                while let Some(request) = worker_receiver.next().await {
+                    handle_request(&mut handler, request, &config).await;
+                    let _ = idle_sender.unbounded_send(index);
+                }
+            };
+            if is_blocking {
+                // The futures of a blocking handler never use the runtime, so they can be
+                // driven without one
+                let (done_sender, done_receiver) = oneshot::channel::<()>();
+                thread::Builder::new()
+                    .name(format!("mail-handler-{}", index))
+                    .spawn(move || {
+                        futures::executor::block_on(worker);
+                        let _ = done_sender.send(());
+                    })
+                    .context("Could not start a mail handler thread")?;
+                worker_futures.push(done_receiver.map(|_| ()).boxed());
+            } else {
+                worker_futures.push(runtime::spawn(worker).boxed());
+            }
+            workers.push(worker_sender);
+        }
+        drop(idle_sender);
+
         let fut = runtime::spawn(async move {
-            while let Some(email) = receiver.next().await {
-                println!("{:?}", email.body);
-                let body = email.body;
-                let parsed_body = match mailparse::parse_mail(body.as_bytes()) {
-                    Ok(b) => b,
-                    Err(e) => {
-                        log::error!("Could not parse mail body");
-                        log::error!("{:?}", body);
-                        log::error!("{:?}", e);
-                        log::error!("-- Ignoring email --");
-                        return Ok(());
-                    }
+            while let Some(request) = receiver.next().await {
+                // Wait until a handler is done with its previous request
+                let index = match idle_receiver.next().await {
+                    Some(index) => index,
+                    None => break,
                 };
-                let returner = email.returner;
-
-                let email = Email {
-                    peer_addr: email.peer_addr,
-                    used_ssl: email.used_ssl,
-                    from: email.from,
-                    to: email.to,
-                    body: parsed_body,
-                };
-
-                let result = handler.handle_mail_async(email).await;
-                let _ = returner.send(result);
+                let _ = workers[index].unbounded_send(request);
             }
+            // Closing the channels stops the workers
+            drop(workers);
+            futures::future::join_all(worker_futures).await;
             Ok(())
         });
 
-        (fut.boxed(), Collector { sender })
+        let collector = Collector {
+            sender,
+            pending: Arc::new(AtomicUsize::new(0)),
+            max_pending: config.max_pending_requests,
+        };
+        Ok((fut.boxed(), collector))
+    }
+
+    fn reserve(&self) -> Result<Pending, Busy> {
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= self.max_pending {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            return Err(Busy);
+        }
+        Ok(Pending(self.pending.clone()))
     }
 
     pub async fn collect(
@@ -71,22 +180,306 @@ impl Collector {
         message: &mut State,
         peer_addr: SocketAddr,
         is_ssl: bool,
-    ) -> Result<bool, failure::Error> {
+        role: ListenerRole,
+    ) -> Result<Vec<Verdict>, failure::Error> {
+        // The client keeps the message and tries again later
+        let _pending = match self.reserve() {
+            Ok(pending) => pending,
+            Err(Busy) => {
+                let rejection = Rejection {
+                    code: 451,
+                    enhanced: Some((4, 3, 2)),
+                    message: "Too busy, try again later".to_owned(),
+                    permanent: false,
+                };
+                return Ok(vec![Err(rejection); message.recipient.len()]);
+            }
+        };
         let from = mem::replace(&mut message.from, Default::default());
+        let mail_parameters = mem::replace(&mut message.mail_parameters, Default::default());
         let to = mem::replace(&mut message.recipient, Default::default());
         let body = mem::replace(&mut message.body, Default::default());
         let (sender, receiver) = futures::channel::oneshot::channel();
         self.sender
-            .send(OwnedEmail {
-                from,
-                to,
+            .send(Request::Mail(OwnedEmail {
+                envelope: Envelope {
+                    peer_addr,
+                    role,
+                    used_ssl: is_ssl,
+                    helo_name: message.helo_name.clone(),
+                    authenticated_user: message.authenticated_user.clone(),
+                    from,
+                    mail_parameters,
+                    to,
+                },
                 body,
-                peer_addr,
-                used_ssl: is_ssl,
                 returner: sender,
-            })
+            }))
+            .await?;
+        let result = receiver.await?;
+        Ok(result)
+    }
+
+    pub async fn authenticate(&mut self, credentials: Credentials) -> Result<bool, failure::Error> {
+        let _pending = self.reserve()?;
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        self.sender
+            .send(Request::Authenticate(credentials, sender))
+            .await?;
+        let result = receiver.await?;
+        Ok(result)
+    }
+
+    pub async fn lookup_credentials(
+        &mut self,
+        mechanism: AuthMechanism,
+        username: String,
+    ) -> Result<Option<StoredCredentials>, failure::Error> {
+        let _pending = self.reserve()?;
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        self.sender
+            .send(Request::LookupCredentials(mechanism, username, sender))
+            .await?;
+        let result = receiver.await?;
+        Ok(result)
+    }
+
+    pub async fn validate_hostname(&mut self, hostname: String) -> Result<bool, failure::Error> {
+        let _pending = self.reserve()?;
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        self.sender
+            .send(Request::ValidateHostname(hostname, sender))
+            .await?;
+        let result = receiver.await?;
+        Ok(result)
+    }
+
+    pub async fn validate_sender(
+        &mut self,
+        envelope: Envelope,
+    ) -> Result<SenderDecision, failure::Error> {
+        let _pending = match self.reserve() {
+            Ok(pending) => pending,
+            Err(Busy) => {
+                let reply = Reply::new(451, (4, 3, 2), "Too busy, try again later");
+                return Ok(SenderDecision::Reject(reply));
+            }
+        };
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        self.sender
+            .send(Request::ValidateSender(envelope, sender))
+            .await?;
+        let result = receiver.await?;
+        Ok(result)
+    }
+
+    pub async fn validate_recipient(
+        &mut self,
+        envelope: Envelope,
+        recipient: Mailbox,
+    ) -> Result<RcptDecision, failure::Error> {
+        let _pending = self.reserve()?;
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        self.sender
+            .send(Request::ValidateRecipient(envelope, recipient, sender))
             .await?;
         let result = receiver.await?;
         Ok(result)
     }
 }
+
+/// Logs who sent a message to whom, without any of its content
+fn log_summary(level: log::Level, envelope: &Envelope, body: &ParsedMail, size: usize) {
+    let from = envelope
+        .from
+        .as_ref()
+        .map(ToString::to_string)
+        .unwrap_or_default();
+    let to = envelope
+        .to
+        .iter()
+        .map(|recipient| format!("<{}>", recipient.mailbox))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let message_id = body
+        .headers
+        .get_first_value("Message-ID")
+        .unwrap_or(None)
+        .unwrap_or_else(|| String::from("none"));
+    log::log!(
+        level,
+        "[{}] Received a message of {} bytes from <{}> to {}, Message-ID {}",
+        envelope.peer_addr,
+        size,
+        from,
+        to,
+        message_id
+    );
+}
+
+/// Writes an unparseable message to `dir`, so it can be inspected later
+fn quarantine(dir: &Path, body: &[u8]) -> io::Result<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let file = dir.join(format!("{}-{:016x}.eml", timestamp, rand::random::<u64>()));
+    fs::write(&file, body)?;
+    Ok(file)
+}
+
+async fn handle_request(handler: &mut impl MailHandlerAsync, request: Request, config: &Config) {
+    let email = match request {
+        Request::Mail(email) => email,
+        Request::Authenticate(credentials, returner) => {
+            let result = handler.authenticate(credentials).await;
+            let _ = returner.send(result);
+            return;
+        }
+        Request::LookupCredentials(mechanism, username, returner) => {
+            let result = handler.lookup_credentials(mechanism, username).await;
+            let _ = returner.send(result);
+            return;
+        }
+        Request::ValidateHostname(hostname, returner) => {
+            let result = handler.validate_hostname(&hostname).await;
+            let _ = returner.send(result);
+            return;
+        }
+        Request::ValidateSender(envelope, returner) => {
+            let result = handler.validate_sender(envelope).await;
+            let _ = returner.send(result);
+            return;
+        }
+        Request::ValidateRecipient(envelope, recipient, returner) => {
+            let result = handler.validate_recipient(envelope, recipient).await;
+            let _ = returner.send(result);
+            return;
+        }
+    };
+    let body = email.body;
+    let envelope = email.envelope;
+    let returner = email.returner;
+    let recipients = envelope.to.len();
+
+    let parsed_body = match mailparse::parse_mail(&body) {
+        Ok(b) => b,
+        Err(e) => {
+            log::error!(
+                "[{}] Could not parse mail body: {:?}",
+                envelope.peer_addr,
+                e
+            );
+            if let Some(dir) = &config.quarantine_dir {
+                match quarantine(dir, &body) {
+                    Ok(file) => log::info!("Quarantined the message as {:?}", file),
+                    Err(e) => log::error!("Could not quarantine the message: {:?}", e),
+                }
+            }
+            let verdict = handler.handle_unparseable(envelope, body).await;
+            let _ = returner.send(vec![verdict; recipients]);
+            return;
+        }
+    };
+
+    if let Some(level) = config.message_log_level {
+        log_summary(level, &envelope, &parsed_body, body.len());
+    }
+
+    let email = Email {
+        peer_addr: envelope.peer_addr,
+        role: envelope.role,
+        used_ssl: envelope.used_ssl,
+        helo_name: envelope.helo_name,
+        authenticated_user: envelope.authenticated_user,
+        from: envelope.from,
+        body_type: envelope.mail_parameters.body_type(),
+        smtputf8: envelope.mail_parameters.smtputf8(),
+        envelope_id: envelope.mail_parameters.envelope_id(),
+        dsn_return: envelope.mail_parameters.dsn_return(),
+        mail_parameters: envelope.mail_parameters,
+        to: envelope.to,
+        body: parsed_body,
+        raw_body: &body,
+    };
+
+    let mut result = handler.handle_mail_per_recipient_async(email).await;
+    if result.len() != recipients {
+        log::error!(
+            "The handler returned {} verdicts for {} recipients",
+            result.len(),
+            recipients
+        );
+        result = vec![Err(Rejection::temporary("Internal server error")); recipients];
+    }
+    let _ = returner.send(result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verdict::Accepted;
+    use crate::MailHandler;
+    use std::time::Duration;
+
+    /// Waits for a runtime timer, so it fails if it is not run on the runtime
+    struct AsyncHandler;
+
+    impl MailHandlerAsync for AsyncHandler {
+        fn handle_mail_async(&mut self, _mail: Email) -> crate::Future<Verdict> {
+            futures::future::ready(Ok(Accepted::default())).boxed()
+        }
+
+        fn validate_hostname(&mut self, hostname: &str) -> crate::Future<bool> {
+            let is_valid = hostname == "client.example";
+            async move {
+                runtime::time::Delay::new(Duration::from_millis(10)).await;
+                is_valid
+            }
+            .boxed()
+        }
+    }
+
+    struct BlockingHandler;
+
+    impl MailHandler for BlockingHandler {
+        fn handle_mail(&mut self, _mail: Email) -> Verdict {
+            Ok(Accepted::default())
+        }
+
+        fn validate_hostname(&mut self, hostname: &str) -> bool {
+            std::thread::sleep(Duration::from_millis(10));
+            hostname == "client.example"
+        }
+    }
+
+    async fn validate_hostnames(handlers: Vec<impl MailHandlerAsync + 'static>) {
+        let config = Config::build("localhost").build();
+        let (server, mut collector) = Collector::spawn(handlers, &config).await.unwrap();
+        for _ in 0..2 {
+            let is_valid = collector.validate_hostname("client.example".to_owned());
+            assert!(is_valid.await.unwrap());
+            let is_valid = collector.validate_hostname("other.example".to_owned());
+            assert!(!is_valid.await.unwrap());
+        }
+        drop(collector);
+        server.await.unwrap();
+    }
+
+    #[runtime::test]
+    async fn async_handlers_can_use_the_runtime() {
+        validate_hostnames(vec![AsyncHandler]).await;
+    }
+
+    #[runtime::test]
+    async fn blocking_handlers_run_on_their_own_thread() {
+        validate_hostnames(vec![BlockingHandler, BlockingHandler]).await;
+    }
+
+    #[runtime::test]
+    async fn a_pool_needs_a_handler() {
+        let config = Config::build("localhost").build();
+        let handlers: Vec<AsyncHandler> = Vec::new();
+        assert!(Collector::spawn(handlers, &config).await.is_err());
+    }
+}
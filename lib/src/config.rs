@@ -1,15 +1,29 @@
+use crate::auth::AuthMechanism;
+use crate::tls_stream::TlsAcceptor;
+use failure::{format_err, ResultExt};
 use std::borrow::Cow;
-/*use std::path::Path;
-use std::io::Read;
 use std::fs::File;
-use failure::{ResultExt, format_err};*/
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub(crate) host: String,
     pub(crate) max_size: usize,
-    // pub tls_acceptor: Option<tokio_tls::TlsAcceptor>,
+    pub(crate) tls_acceptor: Option<TlsAcceptor>,
     pub(crate) features: Vec<ConfigFeature>,
+    pub(crate) listeners: Vec<Listener>,
+    pub(crate) allow_insecure_auth: bool,
+    pub(crate) quarantine_dir: Option<PathBuf>,
+    pub(crate) max_pending_requests: usize,
+    pub(crate) message_log_level: Option<log::Level>,
+    pub(crate) transcript_peers: Vec<IpAddr>,
+    pub(crate) greeting_timeout: Duration,
+    pub(crate) command_timeout: Duration,
+    pub(crate) data_timeout: Duration,
+    pub(crate) session_timeout: Option<Duration>,
 }
 
 impl Config {
@@ -17,54 +31,212 @@ impl Config {
         ConfigBuilder {
             host: host.into(),
             max_size: 4 * 1024 * 1024, // 4MB
+            max_pending_requests: 100,
+            message_log_level: Some(log::Level::Info),
+            // RFC 5321 section 4.5.3.2
+            greeting_timeout: Duration::from_secs(5 * 60),
+            command_timeout: Duration::from_secs(5 * 60),
+            data_timeout: Duration::from_secs(10 * 60),
             ..Default::default()
         }
     }
+
+    pub(crate) fn auth_mechanisms(&self) -> &[AuthMechanism] {
+        self.features
+            .iter()
+            .find_map(|f| match f {
+                ConfigFeature::Auth(mechanisms) => Some(mechanisms.as_slice()),
+                _ => None,
+            })
+            .unwrap_or(&[])
+    }
+
+    pub(crate) fn has_transcript(&self, peer_addr: SocketAddr) -> bool {
+        self.transcript_peers.contains(&peer_addr.ip())
+    }
+
+    /// The level the lines of a session with `peer_addr` are logged at
+    pub(crate) fn transcript_level(&self, peer_addr: SocketAddr) -> log::Level {
+        if self.has_transcript(peer_addr) {
+            log::Level::Debug
+        } else {
+            log::Level::Trace
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub enum ConfigFeature {
-    Auth(String),
+    Auth(Vec<AuthMechanism>),
     Tls,
 }
 
 impl ConfigFeature {
     pub fn as_ehlo_tag(&self) -> Option<Cow<'static, str>> {
         match self {
-            ConfigFeature::Auth(s) => Some(format!("AUTH {}", s).into()),
+            ConfigFeature::Auth(mechanisms) => {
+                let mut tag = String::from("AUTH");
+                for mechanism in mechanisms {
+                    tag += " ";
+                    tag += mechanism.name();
+                }
+                Some(tag.into())
+            }
             ConfigFeature::Tls => Some("STARTTLS".into()),
         }
     }
 }
 
+/// The purpose of a listening socket. This is passed on to every `Email` received on it.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ListenerRole {
+    /// Mail relayed from other mail servers, usually on port 25
+    Relay,
+    /// Mail submitted by mail clients, usually on port 587
+    Submission,
+    /// Mail submitted by mail clients over implicit TLS, usually on port 465
+    ImplicitTls,
+    /// Local delivery with LMTP (RFC 2033), e.g. from a mail server on the same machine. The
+    /// client greets with `LHLO`, and gets one reply per recipient after the message.
+    Lmtp,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Listener {
+    pub(crate) addr: SocketAddr,
+    pub(crate) role: ListenerRole,
+}
+
 #[derive(Default)]
 pub struct ConfigBuilder {
     host: String,
     max_size: usize,
-    // tls_acceptor: Option<tokio_tls::TlsAcceptor>,
+    tls_acceptor: Option<TlsAcceptor>,
     features: Vec<ConfigFeature>,
+    listeners: Vec<Listener>,
+    allow_insecure_auth: bool,
+    quarantine_dir: Option<PathBuf>,
+    max_pending_requests: usize,
+    message_log_level: Option<log::Level>,
+    transcript_peers: Vec<IpAddr>,
+    greeting_timeout: Duration,
+    command_timeout: Duration,
+    data_timeout: Duration,
+    session_timeout: Option<Duration>,
 }
 
 impl ConfigBuilder {
-    /*pub fn with_tls_from_pfx(mut self, file: impl AsRef<Path>) -> Result<ConfigBuilder, failure::Error> {
+    /// Enable TLS with the identity in the given PKCS #12 file. The file may not have a
+    /// password.
+    pub fn with_tls_from_pfx(
+        self,
+        file: impl AsRef<Path>,
+    ) -> Result<ConfigBuilder, failure::Error> {
         let file = file.as_ref();
-        let mut file = File::open(file).with_context(|e| format_err!("Could not load {:?}: {:?}", file, e))?;
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents)
-            .with_context(|e| format_err!("Could not read {:?}: {:?}", file, e))?;
-        drop(file);
-
-        let acceptor = native_tls::TlsAcceptor::new(
-            native_tls::Identity::from_pkcs12(&contents, "").with_context(|e| format_err!("Could not parse {:?}: {:?}", file, e))?,
-        )
-        .context("Could not create a TLS Acceptor")?;
-        let acceptor = tokio_tls::TlsAcceptor::from(acceptor);
+        let contents = read_file(file)?;
+        let identity = native_tls::Identity::from_pkcs12(&contents, "")
+            .with_context(|e| format_err!("Could not parse {:?}: {:?}", file, e))?;
+        self.with_tls_identity(identity)
+    }
+
+    /// Enable TLS with the given PEM encoded certificate chain and PKCS #8 private key.
+    pub fn with_tls_from_pem(
+        self,
+        certificate_file: impl AsRef<Path>,
+        key_file: impl AsRef<Path>,
+    ) -> Result<ConfigBuilder, failure::Error> {
+        let certificate = read_file(certificate_file.as_ref())?;
+        let key = read_file(key_file.as_ref())?;
+        let identity = native_tls::Identity::from_pkcs8(&certificate, &key)
+            .with_context(|e| format_err!("Could not parse the PEM certificate or key: {:?}", e))?;
+        self.with_tls_identity(identity)
+    }
+
+    /// This advertises STARTTLS, and is required for `ListenerRole::ImplicitTls` listeners.
+    fn with_tls_identity(
+        mut self,
+        identity: native_tls::Identity,
+    ) -> Result<ConfigBuilder, failure::Error> {
+        let acceptor =
+            native_tls::TlsAcceptor::new(identity).context("Could not create a TLS Acceptor")?;
 
         self.features.push(ConfigFeature::Tls);
-        self.tls_acceptor = Some(acceptor);
+        self.tls_acceptor = Some(acceptor.into());
 
         Ok(self)
-    }*/
+    }
+
+    /// Accept `AUTH` with the given mechanisms. `PLAIN` and `LOGIN` are checked by
+    /// `MailHandler::authenticate`, `CRAM-MD5` and `SCRAM-SHA-256` are verified against
+    /// `MailHandler::lookup_credentials`.
+    pub fn with_auth(mut self, mechanisms: &[AuthMechanism]) -> Self {
+        self.features.push(ConfigFeature::Auth(mechanisms.to_vec()));
+        self
+    }
+
+    /// Allow `AUTH PLAIN` and `AUTH LOGIN` on connections that are not encrypted. This sends
+    /// passwords in plain text, so it should only be used on trusted networks.
+    pub fn allow_insecure_auth(mut self) -> Self {
+        self.allow_insecure_auth = true;
+        self
+    }
+
+    /// Save a copy of every message that can not be parsed in `dir`, before it is passed to
+    /// `MailHandler::handle_unparseable`. The directory has to exist.
+    pub fn quarantine_unparseable(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.quarantine_dir = Some(dir.into());
+        self
+    }
+
+    /// The number of handler calls that may be waiting or running at the same time, across all
+    /// connections. When there are more, new mail transactions get a 451 reply and other
+    /// commands a 421 reply. The default is 100.
+    pub fn max_pending_requests(mut self, max_pending_requests: usize) -> Self {
+        self.max_pending_requests = max_pending_requests;
+        self
+    }
+
+    /// Log a summary of every received message at `level`: its size, sender, recipients and
+    /// Message-ID, but nothing of its content. `None` turns this off. The default is `Info`.
+    pub fn log_messages(mut self, level: impl Into<Option<log::Level>>) -> Self {
+        self.message_log_level = level.into();
+        self
+    }
+
+    /// Log everything that is sent to and received from `peer` at the `Debug` level, including
+    /// the messages. Only authentication data is left out. Other peers are logged at the `Trace`
+    /// level, without the content of their messages.
+    pub fn transcript_peer(mut self, peer: impl Into<IpAddr>) -> Self {
+        self.transcript_peers.push(peer.into());
+        self
+    }
+
+    /// How long a new client has to finish the TLS handshake of `ListenerRole::ImplicitTls` or
+    /// `STARTTLS`, and to introduce itself with `EHLO` or `HELO`. The default is 5 minutes.
+    pub fn greeting_timeout(mut self, timeout: Duration) -> Self {
+        self.greeting_timeout = timeout;
+        self
+    }
+
+    /// How long to wait for the next command, and for the client to read the replies. The
+    /// default is 5 minutes.
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = timeout;
+        self
+    }
+
+    /// How long to wait for the next line of a `DATA` message or the next `BDAT` chunk. The
+    /// default is 10 minutes.
+    pub fn data_timeout(mut self, timeout: Duration) -> Self {
+        self.data_timeout = timeout;
+        self
+    }
+
+    /// How long a connection may stay open in total. By default there is no limit.
+    pub fn session_timeout(mut self, timeout: Duration) -> Self {
+        self.session_timeout = Some(timeout);
+        self
+    }
 
     pub fn max_message_size_kb(mut self, max_size_kb: usize) -> Self {
         self.max_size = max_size_kb * 1024;
@@ -75,12 +247,51 @@ impl ConfigBuilder {
         self
     }
 
-    pub fn build(self) -> Config {
+    /// Listen on the given address. This can be called multiple times to listen on multiple
+    /// addresses, e.g. `(Ipv6Addr::UNSPECIFIED, 25)` or `(Ipv4Addr::LOCALHOST, 2525)`.
+    ///
+    /// Port 0 lets the OS pick a free port, which `ServerHandle::local_addrs` reports. If this
+    /// is never called, the server listens on `0.0.0.0:25` as a relay.
+    pub fn listen(mut self, addr: impl Into<SocketAddr>, role: ListenerRole) -> Self {
+        self.listeners.push(Listener {
+            addr: addr.into(),
+            role,
+        });
+        self
+    }
+
+    pub fn build(mut self) -> Config {
+        if self.listeners.is_empty() {
+            self.listeners.push(Listener {
+                addr: (Ipv4Addr::UNSPECIFIED, 25).into(),
+                role: ListenerRole::Relay,
+            });
+        }
         Config {
             host: self.host,
             max_size: self.max_size,
-            // tls_acceptor: self.tls_acceptor,
+            tls_acceptor: self.tls_acceptor,
             features: self.features,
+            listeners: self.listeners,
+            allow_insecure_auth: self.allow_insecure_auth,
+            quarantine_dir: self.quarantine_dir,
+            max_pending_requests: self.max_pending_requests,
+            message_log_level: self.message_log_level,
+            transcript_peers: self.transcript_peers,
+            greeting_timeout: self.greeting_timeout,
+            command_timeout: self.command_timeout,
+            data_timeout: self.data_timeout,
+            session_timeout: self.session_timeout,
         }
     }
 }
+
+fn read_file(file: &Path) -> Result<Vec<u8>, failure::Error> {
+    let mut file_handle =
+        File::open(file).with_context(|e| format_err!("Could not load {:?}: {:?}", file, e))?;
+    let mut contents = Vec::new();
+    file_handle
+        .read_to_end(&mut contents)
+        .with_context(|e| format_err!("Could not read {:?}: {:?}", file, e))?;
+    Ok(contents)
+}
@@ -1,164 +1,424 @@
-use crate::collector::Collector;
-use crate::config::{Config, ConfigFeature};
+use crate::address::{parse_domain, parse_path, Mailbox, Recipient};
+use crate::auth::{self, AuthMechanism, Credentials};
+use crate::collector::{Busy, Collector};
+use crate::config::{Config, ConfigFeature, ListenerRole};
+use crate::dsn::{self, DsnReturn, Notify, OriginalRecipient};
+use crate::envelope::{Envelope, RcptDecision, SenderDecision};
+use crate::line_reader::LineReader;
 use crate::message_parser::MessageParser;
-use futures::{FutureExt, Sink, SinkExt, StreamExt};
+use crate::parameters::{BodyType, Parameters};
+use crate::reply::Reply;
+use crate::shutdown::ShutdownSignal;
+use crate::tls_stream::TlsStream;
+use crate::verdict;
+use failure::Fail;
+use futures::future::{self, Either};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::{FutureExt, SinkExt, StreamExt};
 use runtime::net::TcpStream;
+use runtime::time::Delay;
 use std::borrow::Cow;
+use std::cmp;
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-/*
-pub fn spawn_tls(client: TcpStream, collector: Collector, config: Config) {
-    runtime::spawn(async {
+enum SessionEnd {
+    Quit,
+    Upgrade,
+}
 
+/// A client did not send anything within the timeouts of RFC 5321 section 4.5.3.2
+#[derive(Debug, Fail)]
+#[fail(display = "The client timed out")]
+struct TimedOut;
+
+async fn timeout<F>(future: F, duration: Duration) -> Result<F::Output, TimedOut>
+where
+    F: std::future::Future,
+{
+    pin_utils::pin_mut!(future);
+    match future::select(future, Delay::new(duration)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(TimedOut),
     }
-        run_tls(client, collector, Default::default(), config)
-            .map_err(|e| {
-                log::error!("Could not run connection: {:?}", e);
-            })
-            .boxed()
-            .compat(),
-    );
 }
-*/
-
-type DoBreak = bool;
 
-async fn handle_line<R>(
-    line: &str,
+async fn handle_line<S>(
+    line: &[u8],
     state: &mut State,
     config: &Config,
-    reader: &mut R,
+    reader: &mut LineReader<S>,
     collector: &mut Collector,
     peer_addr: SocketAddr,
-) -> Result<DoBreak, failure::Error>
+    role: ListenerRole,
+) -> Result<Option<SessionEnd>, failure::Error>
 where
-    R: Sink<Vec<u8>> + Unpin,
-    <R as Sink<Vec<u8>>>::Error: 'static + Sync + Send + std::error::Error,
+    S: AsyncRead + AsyncWrite + Unpin,
 {
-    match state.message_received(&line, &config).await {
-        LineResponse::None => {}
-        LineResponse::Upgrade => {
-            log_and_send!(reader, peer_addr, "500 Not implemented");
-            /*log::debug!("Upgrading request");
-            client.write_all(b"220 Go ahead").await?;
-            return run_tls(client, collector, state, config).await;
-            */
-        }
-        LineResponse::ReplyWith(msg) => {
-            log_and_send!(reader, peer_addr, msg);
-        }
-        LineResponse::ReplyWithMultiple(msg) => {
-            for msg in msg {
-                log_and_send!(reader, peer_addr, msg);
-            }
-        }
-        LineResponse::Done => {
-            let collected_ok = collector.collect(state, peer_addr, false).await?;
-            if collected_ok {
-                log_and_send!(reader, peer_addr, "250 Ok: Message received, over");
-            } else {
-                log_and_send!(reader, peer_addr, "500 Internal server error");
+    let mut response = state.message_received(line, &config).await;
+    loop {
+        match response {
+            LineResponse::None => {}
+            LineResponse::Upgrade => {
+                if config.tls_acceptor.is_some() {
+                    send_reply!(
+                        reader,
+                        peer_addr,
+                        Reply::new(220, (2, 0, 0), "Ready to start TLS")
+                    );
+                    return Ok(Some(SessionEnd::Upgrade));
+                } else {
+                    send_reply!(
+                        reader,
+                        peer_addr,
+                        Reply::new(454, (4, 7, 0), "TLS not available")
+                    );
+                }
+            }
+            LineResponse::Reply(reply) => {
+                send_reply!(reader, peer_addr, reply);
+            }
+            LineResponse::Authenticate(credentials) => {
+                let username = credentials.username.clone();
+                let step = if collector.authenticate(credentials).await? {
+                    auth::Step::Authenticated(username)
+                } else {
+                    auth::Step::Rejected(username)
+                };
+                response = state.auth_step(step);
+                continue;
+            }
+            LineResponse::ValidateHostname { name, extended } => {
+                let is_valid = collector.validate_hostname(name.clone()).await?;
+                response = state.hostname_validated(name, extended, is_valid, config);
+                continue;
+            }
+            LineResponse::ValidateSender { from, parameters } => {
+                let mut envelope = state.envelope(peer_addr, role);
+                envelope.from = from.clone();
+                envelope.mail_parameters = parameters.clone();
+                let decision = collector.validate_sender(envelope).await?;
+                response = state.sender_validated(from, parameters, decision);
+                continue;
+            }
+            LineResponse::ValidateRecipient(recipient) => {
+                let envelope = state.envelope(peer_addr, role);
+                let decision = collector
+                    .validate_recipient(envelope, recipient.mailbox.clone())
+                    .await?;
+                response = state.recipient_validated(recipient, decision);
+                continue;
+            }
+            LineResponse::LookupCredentials(lookup) => {
+                let credentials = collector
+                    .lookup_credentials(lookup.mechanism(), lookup.username().to_owned())
+                    .await?;
+                response = state.auth_step(lookup.finish(credentials));
+                continue;
+            }
+            LineResponse::ReadChunk { size, last } => {
+                let start = state.body.len();
+                let wait = state.session_limit(config.data_timeout);
+                let chunk = reader.read_chunk(size, Some(&mut state.body));
+                timeout(chunk, wait).await??;
+                if config.has_transcript(peer_addr) {
+                    let chunk = String::from_utf8_lossy(&state.body[start..]);
+                    log::debug!("[{}]  IN: {}", peer_addr, chunk);
+                } else {
+                    log::trace!("[{}]  IN: <{} bytes of BDAT data>", peer_addr, size);
+                }
+                if last {
+                    response = LineResponse::Done;
+                    continue;
+                }
+                let reply = Reply::new(250, (2, 0, 0), format!("{} octets received", size));
+                send_reply!(reader, peer_addr, reply);
+            }
+            LineResponse::DiscardChunk { size, reply } => {
+                // RFC 3030 section 2: the chunk is sent regardless of the reply, so it has to be
+                // read before the next command
+                let wait = state.session_limit(config.data_timeout);
+                timeout(reader.read_chunk(size, None), wait).await??;
+                send_reply!(reader, peer_addr, reply);
+            }
+            LineResponse::Done => {
+                let used_ssl = state.is_tls;
+                let verdicts = collector.collect(state, peer_addr, used_ssl, role).await?;
+                for rejection in verdicts.iter().filter_map(|v| v.as_ref().err()) {
+                    log::info!("[{}] Message rejected: {:?}", peer_addr, rejection);
+                }
+                let replies: Vec<Reply> = if state.is_lmtp {
+                    // RFC 2033 section 4.2: one reply for every accepted recipient, in order
+                    verdicts.iter().map(verdict::reply).collect()
+                } else {
+                    vec![verdict::reply(&verdict::combine(verdicts))]
+                };
+                for reply in replies {
+                    send_reply!(reader, peer_addr, reply);
+                }
+                state.reset();
+            }
+            LineResponse::Quit => {
+                send_reply!(
+                    reader,
+                    peer_addr,
+                    Reply::new(221, (2, 0, 0), "Come back soon!")
+                );
+                return Ok(Some(SessionEnd::Quit));
             }
-            *state = Default::default();
-        }
-        LineResponse::Quit => {
-            log_and_send!(reader, peer_addr, "200 Come back soon!");
-            return Ok(true);
         }
+        break;
     }
-    Ok(false)
+    Ok(None)
 }
+
 pub async fn run(
     client: TcpStream,
     mut collector: Collector,
     config: Config,
+    role: ListenerRole,
+    shutdown: ShutdownSignal,
 ) -> Result<(), failure::Error> {
-    let mut state = State::default();
     let addr = client.peer_addr()?;
+    let session_end = config
+        .session_timeout
+        .map(|session_timeout| Instant::now() + session_timeout);
 
-    let mut reader = crate::line_reader::LineReader::new(client, config.max_size);
-    log_and_send!(
-        reader,
-        addr,
-        "220 {} ESMTP MailServer",
-        config.host.as_str()
-    );
-
-    while let Some(line) = reader.next().await {
-        let line = line?;
-        log::trace!("[{}]  IN: {}", addr, line);
-        let do_break = handle_line(
-            &line,
-            &mut state,
+    if role == ListenerRole::ImplicitTls {
+        // RFC 8314 section 3.3: the TLS handshake happens before the server greeting
+        let stream = timeout(accept_tls(&config, client, addr), config.greeting_timeout).await??;
+        let mut reader = LineReader::new(stream, config.max_size, config.transcript_level(addr));
+        send_greeting(&mut reader, &config, addr, role).await?;
+        run_session(
+            &mut reader,
+            State::new(role, true, session_end),
             &config,
+            &mut collector,
+            addr,
+            role,
+            &shutdown,
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let mut reader = LineReader::new(client, config.max_size, config.transcript_level(addr));
+    send_greeting(&mut reader, &config, addr, role).await?;
+
+    let end = run_session(
+        &mut reader,
+        State::new(role, false, session_end),
+        &config,
+        &mut collector,
+        addr,
+        role,
+        &shutdown,
+    )
+    .await?;
+
+    if let Some(SessionEnd::Upgrade) = end {
+        let handshake = accept_tls(&config, reader.into_inner(), addr);
+        let stream = timeout(handshake, config.greeting_timeout).await??;
+        let mut reader = LineReader::new(stream, config.max_size, config.transcript_level(addr));
+        // RFC 3207 section 4.2: the client has to start over with EHLO, and everything that
+        // was received before the handshake has to be discarded
+        run_session(
             &mut reader,
+            State::new(role, true, session_end),
+            &config,
             &mut collector,
             addr,
+            role,
+            &shutdown,
         )
         .await?;
-        if do_break {
-            break;
-        }
     }
     Ok(())
 }
-/*
-async fn run_tls(
+
+async fn accept_tls(
+    config: &Config,
     client: TcpStream,
-    collector: Collector,
-    mut state: State,
-    config: Config,
-) -> Result<(), failure::Error> {
-    let config = config.read();
-    let tls_acceptor = match config.tls_acceptor.as_ref() {
-        Some(t) => t,
-        None => {
-            log::error!("Tried to accept TLS connection, but TLS was not configured");
-            log::error!("Please call `config_builder.with_tls_from_pfx(\"identity.pfx\").expect(\"Could not load identity.pfx\")`");
-            failure::bail!("TLS not implemented");
-        }
-    };
-    let stream = Compat01As03::new(tls_acceptor.accept(client)).await?;
+    addr: SocketAddr,
+) -> Result<TlsStream<TcpStream>, failure::Error> {
+    let tls_acceptor = config
+        .tls_acceptor
+        .as_ref()
+        .ok_or_else(|| failure::format_err!("TLS was requested but is not configured"))?;
+    let stream = tls_acceptor.accept(client).await?;
+    log::debug!("[{}] TLS handshake completed", addr);
+    Ok(stream)
+}
 
-    let reader = LinesCodec::new().framed(stream);
-    let (sink, stream) = reader.split();
-    let mut sink = Compat01As03Sink::<_, String>::new(sink);
-    let mut stream = Compat01As03::new(stream);
+async fn send_greeting<S>(
+    reader: &mut LineReader<S>,
+    config: &Config,
+    addr: SocketAddr,
+    role: ListenerRole,
+) -> Result<(), failure::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let protocol = if role == ListenerRole::Lmtp {
+        "LMTP"
+    } else {
+        "ESMTP"
+    };
+    let greeting = format!("{} {} MailServer", config.host, protocol);
+    send_reply!(reader, addr, Reply::new(220, None, greeting));
+    // A client that does not read its replies would block the flush forever
+    timeout(reader.flush(), config.greeting_timeout).await??;
+    Ok(())
+}
 
-    while let Some(line) = stream.next().await {
-        let line: String = line?;
-        match state.message_received(&line) {
-            LineResponse::None => {}
-            LineResponse::Upgrade => {
-                sink.send(String::from("500 Already upgraded")).await?;
+async fn run_session<S>(
+    reader: &mut LineReader<S>,
+    mut state: State,
+    config: &Config,
+    collector: &mut Collector,
+    addr: SocketAddr,
+    role: ListenerRole,
+    shutdown: &ShutdownSignal,
+) -> Result<Option<SessionEnd>, failure::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let shutdown_started = shutdown.wait();
+    pin_utils::pin_mut!(shutdown_started);
+    loop {
+        let wait = state.line_timeout(config);
+        let next = if state.is_receiving_message() {
+            // A message that is being received is finished, even if the server shuts down
+            timeout(reader.next(), wait).await.map(Some)
+        } else {
+            // An idle session is closed as soon as the server shuts down
+            let next = future::select(reader.next(), shutdown_started.as_mut());
+            timeout(next, wait).await.map(|next| match next {
+                Either::Left((line, _)) => Some(line),
+                Either::Right(_) => None,
+            })
+        };
+        let next = match next {
+            Ok(Some(None)) => break,
+            Ok(Some(Some(line))) => {
+                if state.is_receiving_message() || !shutdown.is_shutting_down() {
+                    Ok(line?)
+                } else {
+                    Err(shutdown_reply(config))
+                }
             }
-            LineResponse::ReplyWith(msg) => {
-                sink.send(msg.into_owned()).await?;
+            Ok(None) => Err(shutdown_reply(config)),
+            Err(TimedOut) => {
+                log::info!("[{}] Timed out", addr);
+                Err(timeout_reply(config))
             }
-            LineResponse::ReplyWithMultiple(msg) => {
-                for msg in msg {
-                    sink.send(msg.into_owned()).await?;
-                }
+        };
+        let line = match next {
+            Ok(line) => line,
+            Err(reply) => {
+                send_reply!(reader, addr, reply);
+                let wait = state.session_limit(config.command_timeout);
+                timeout(reader.flush(), wait).await??;
+                return Ok(Some(SessionEnd::Quit));
             }
-            LineResponse::Done => {
-                sink.send(String::from("250 Ok: Message received, over"))
-                    .await?;
-                collector.collect(&mut state);
-                state = Default::default();
-            } // LineResponse::Err(e) => return Err(e),
+        };
+        let level = reader.log_level();
+        if state.is_authenticating(&line) {
+            log::log!(level, "[{}]  IN: <authentication data>", addr);
+        } else if state.phase != Phase::Data || config.has_transcript(addr) {
+            // The content of messages is only logged for peers with a transcript
+            log::log!(level, "[{}]  IN: {}", addr, String::from_utf8_lossy(&line));
+        }
+        let result = handle_line(&line, &mut state, config, reader, collector, addr, role).await;
+        let end = match result {
+            Err(ref e) if e.downcast_ref::<Busy>().is_some() => {
+                let reply = Reply::new(
+                    421,
+                    (4, 3, 2),
+                    format!("{} Too busy, try again later", config.host),
+                );
+                send_reply!(reader, addr, reply);
+                Some(SessionEnd::Quit)
+            }
+            Err(ref e) if e.downcast_ref::<TimedOut>().is_some() => {
+                log::info!("[{}] Timed out", addr);
+                send_reply!(reader, addr, timeout_reply(config));
+                Some(SessionEnd::Quit)
+            }
+            result => result?,
+        };
+        // RFC 2920 section 3.2: the replies to a group of pipelined commands are sent together,
+        // as soon as there are no more commands waiting to be handled
+        if end.is_some() || !reader.has_buffered_line() {
+            // A client that does not read its replies would block the flush forever
+            let wait = state.session_limit(config.command_timeout);
+            timeout(reader.flush(), wait).await??;
+        }
+        if end.is_some() {
+            return Ok(end);
         }
     }
-    Ok(())
+    Ok(None)
+}
+
+fn shutdown_reply(config: &Config) -> Reply {
+    Reply::new(
+        421,
+        (4, 3, 2),
+        format!("{} Shutting down, try again later", config.host),
+    )
+}
+
+fn timeout_reply(config: &Config) -> Reply {
+    Reply::new(
+        421,
+        (4, 4, 2),
+        format!("{} Timeout, closing connection", config.host),
+    )
 }
-*/
 
 #[derive(Default, Debug)]
 pub struct State {
-    pub from: String,
-    pub recipient: Vec<String>,
-    pub body: String,
-    ehlo_received: bool,
-    is_reading_body: bool,
+    pub from: Option<Mailbox>,
+    pub mail_parameters: Parameters,
+    pub recipient: Vec<Recipient>,
+    pub body: Vec<u8>,
+    phase: Phase,
+    /// Set when the message exceeded `Config::max_size` during `DATA`
+    is_body_too_large: bool,
+    is_tls: bool,
+    /// Set on `ListenerRole::Lmtp` listeners
+    is_lmtp: bool,
+    /// The parsed argument of `EHLO` or `HELO`
+    pub helo_name: Option<String>,
+    pub authenticated_user: Option<String>,
+    auth_exchange: Option<auth::Exchange>,
+    /// When the connection is closed, if `Config::session_timeout` is set
+    session_end: Option<Instant>,
+}
+
+/// Where the client is in the command sequence of RFC 5321 section 4.1.4. Commands that are
+/// sent out of order are answered with `503 Bad sequence of commands`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    /// The greeting was sent, but the client did not introduce itself yet
+    Connected,
+    /// `EHLO` or `HELO` was received, and no mail transaction is in progress
+    Greeted,
+    /// `MAIL FROM` was accepted
+    MailFrom,
+    /// At least one `RCPT TO` was accepted
+    RcptTo,
+    /// `DATA` was accepted, every line up to the final `.` is part of the message
+    Data,
+    /// At least one `BDAT` chunk was accepted, and more chunks follow
+    Bdat,
+}
+
+impl Default for Phase {
+    fn default() -> Phase {
+        Phase::Connected
+    }
 }
 
 type Future<T> = std::pin::Pin<Box<dyn std::future::Future<Output = T> + Send>>;
@@ -168,14 +428,17 @@ lazy_static::lazy_static! {
     static ref SMTP_COMMANDS: std::collections::HashMap<&'static [u8; 4], StateFn> = {
         let mut map = std::collections::HashMap::<&'static [u8; 4], StateFn>::new();
         map.insert(b"EHLO", &handle_ehlo);
+        map.insert(b"HELO", &handle_helo);
+        map.insert(b"LHLO", &handle_lhlo);
         map.insert(b"MAIL", &handle_mail);
         map.insert(b"RCPT", &handle_recipient);
-        map.insert(b"SIZE", &handle_size);
         map.insert(b"DATA", &handle_data);
+        map.insert(b"BDAT", &handle_bdat);
         map.insert(b"VRFY", &handle_verify);
         map.insert(b"TURN", &handle_turn);
         map.insert(b"AUTH", &handle_auth);
         map.insert(b"RSET", &handle_reset);
+        map.insert(b"NOOP", &handle_noop);
         map.insert(b"EXPN", &handle_expn);
         map.insert(b"HELP", &handle_help);
         map.insert(b"QUIT", &handle_quit);
@@ -183,101 +446,345 @@ lazy_static::lazy_static! {
     };
 }
 
-fn handle_ehlo(state: &mut State, _parser: MessageParser, config: &Config) -> Future<LineResponse> {
-    state.ehlo_received = true;
-    let mut cmds_to_send: Vec<Cow<'static, str>> = Vec::new();
-    cmds_to_send.push("localhost, I'm glad to meet you".into());
-    cmds_to_send.push(format!("SIZE {}", config.max_size).into());
+fn handle_ehlo(state: &mut State, parser: MessageParser, _config: &Config) -> Future<LineResponse> {
+    futures::future::ready(if state.is_lmtp {
+        Reply::new(500, (5, 5, 1), "This is LMTP, send LHLO").into()
+    } else {
+        parse_hello(parser, "EHLO", true)
+    })
+    .boxed()
+}
 
-    for feature in &config.features {
-        if let Some(tag) = feature.as_ehlo_tag() {
-            cmds_to_send.push(tag);
-        }
-    }
+fn handle_helo(state: &mut State, parser: MessageParser, _config: &Config) -> Future<LineResponse> {
+    futures::future::ready(if state.is_lmtp {
+        Reply::new(500, (5, 5, 1), "This is LMTP, send LHLO").into()
+    } else {
+        parse_hello(parser, "HELO", false)
+    })
+    .boxed()
+}
 
-    let mut responses = Vec::with_capacity(cmds_to_send.len());
-    for cmd in cmds_to_send.iter().take(cmds_to_send.len() - 1) {
-        responses.push(format!("250-{}", cmd).into());
-    }
-    // There are always items in cmds_to_send, so this .last().unwrap() is safe
-    responses.push(format!("250 {}", cmds_to_send.last().unwrap()).into());
+/// `LHLO` is the `EHLO` of LMTP, see RFC 2033 section 4.1
+fn handle_lhlo(state: &mut State, parser: MessageParser, _config: &Config) -> Future<LineResponse> {
+    futures::future::ready(if !state.is_lmtp {
+        Reply::new(500, (5, 5, 2), "Unknown command").into()
+    } else {
+        parse_hello(parser, "LHLO", true)
+    })
+    .boxed()
+}
 
-    futures::future::ready(LineResponse::ReplyWithMultiple(responses)).boxed()
+/// Parses `Domain / address-literal` of RFC 5321 section 4.1.1.1. Only the parsed name is
+/// kept, anything after it is refused.
+fn parse_hello(mut parser: MessageParser, command: &str, extended: bool) -> LineResponse {
+    let invalid = || Reply::new(501, (5, 5, 4), format!("Expected {} <hostname>", command));
+    let name = match parse_domain(&mut parser) {
+        Ok(name) => name,
+        Err(_) => return invalid().into(),
+    };
+    if !parser.rest().is_empty() {
+        return invalid().into();
+    }
+    LineResponse::ValidateHostname { name, extended }
 }
 
 fn handle_mail(
     state: &mut State,
     mut parser: MessageParser,
-    _config: &Config,
+    config: &Config,
 ) -> Future<LineResponse> {
-    futures::future::ready(if !state.ehlo_received {
-        "500 Aren't you supposed to introduce yourself? (Send EHLO)".into()
+    futures::future::ready(if state.phase == Phase::Connected {
+        Reply::new(
+            503,
+            (5, 5, 1),
+            "Aren't you supposed to introduce yourself? (Send EHLO)",
+        )
+        .into()
+    } else if state.phase != Phase::Greeted {
+        Reply::new(503, (5, 5, 1), "Nested MAIL command").into()
     } else {
         match parser.consume_word_until(COLON) {
             Some(word) => {
                 let word = word.to_ascii_uppercase();
                 if word == "FROM" {
                     log::trace!("[MAIL] from {}", parser.remaining());
-                    state.from = parser.remaining().to_owned();
-
-                    format!("250 Say hi to {} for me", parser.remaining()).into()
+                    match parse_mail_from(&mut parser, config) {
+                        Ok((from, parameters)) => LineResponse::ValidateSender { from, parameters },
+                        Err(reply) => reply.into(),
+                    }
                 } else {
-                    "500 Expected FROM after MAIL".into()
+                    Reply::new(501, (5, 5, 4), "Expected FROM after MAIL").into()
                 }
             }
-            None => "500 Expected FROM after MAIL".into(),
+            None => Reply::new(501, (5, 5, 4), "Expected FROM after MAIL").into(),
         }
     })
     .boxed()
 }
 
+/// The `MAIL FROM` parameters that are advertised in `handle_ehlo`
+const MAIL_PARAMETERS: &[&str] = &["SIZE", "BODY", "SMTPUTF8", "ENVID", "RET"];
+/// The `RCPT TO` parameters that are advertised in `handle_ehlo`
+const RCPT_PARAMETERS: &[&str] = &["NOTIFY", "ORCPT"];
+
+/// Parses `"FROM:" Reverse-path [SP Mail-parameters]`, where `FROM:` is already consumed
+fn parse_mail_from(
+    parser: &mut MessageParser,
+    config: &Config,
+) -> Result<(Option<Mailbox>, Parameters), Reply> {
+    parser.consume_while(|b| b == SPACE);
+    let from = parse_path(parser).map_err(|e| e.reply((5, 1, 7)))?;
+    let parameters =
+        Parameters::parse(parser.remaining()).map_err(|e| Reply::new(501, (5, 5, 4), e))?;
+    check_known_parameters(&parameters, MAIL_PARAMETERS)?;
+
+    if parameters.contains("SIZE") {
+        // RFC 1870 section 6: reject the message before it is transferred
+        match parameters.value("SIZE").map(str::parse::<usize>) {
+            Some(Ok(size)) if size > config.max_size => {
+                return Err(Reply::new(
+                    552,
+                    (5, 3, 4),
+                    format!(
+                        "Message size exceeds fixed maximum message size of {} bytes",
+                        config.max_size
+                    ),
+                ));
+            }
+            Some(Ok(_)) => {}
+            _ => return Err(Reply::new(501, (5, 5, 4), "Invalid SIZE parameter")),
+        }
+    }
+    if parameters.contains("BODY")
+        && parameters
+            .value("BODY")
+            .and_then(BodyType::from_value)
+            .is_none()
+    {
+        return Err(Reply::new(501, (5, 5, 4), "Invalid BODY parameter"));
+    }
+    if let Some(envelope_id) = parameters.value("ENVID") {
+        // RFC 3461 section 4.4: the ENVID is at most 100 characters
+        if envelope_id.len() > 100 || dsn::decode_xtext(envelope_id).is_none() {
+            return Err(Reply::new(501, (5, 5, 4), "Invalid ENVID parameter"));
+        }
+    }
+    if parameters.contains("RET")
+        && parameters
+            .value("RET")
+            .and_then(DsnReturn::from_value)
+            .is_none()
+    {
+        return Err(Reply::new(501, (5, 5, 4), "Invalid RET parameter"));
+    }
+    if parameters.smtputf8() && parameters.value("SMTPUTF8").is_some() {
+        return Err(Reply::new(501, (5, 5, 4), "SMTPUTF8 does not take a value"));
+    }
+    if let Some(from) = &from {
+        check_utf8_address(from, parameters.smtputf8())?;
+    }
+    Ok((from, parameters))
+}
+
+/// Parses `"TO:" Forward-path [SP Rcpt-parameters]`, where `TO:` is already consumed
+fn parse_rcpt_to(parser: &mut MessageParser, smtputf8: bool) -> Result<Recipient, Reply> {
+    parser.consume_while(|b| b == SPACE);
+    let mailbox = parse_path(parser)
+        .map_err(|e| e.reply((5, 1, 3)))?
+        .ok_or_else(|| Reply::new(553, (5, 1, 3), "The null path is not a valid recipient"))?;
+    let parameters =
+        Parameters::parse(parser.remaining()).map_err(|e| Reply::new(501, (5, 5, 4), e))?;
+    check_known_parameters(&parameters, RCPT_PARAMETERS)?;
+    check_utf8_address(&mailbox, smtputf8)?;
+
+    let notify = match parameters.value("NOTIFY") {
+        Some(value) => Some(
+            Notify::from_value(value)
+                .ok_or_else(|| Reply::new(501, (5, 5, 4), "Invalid NOTIFY parameter"))?,
+        ),
+        None if parameters.contains("NOTIFY") => {
+            return Err(Reply::new(501, (5, 5, 4), "Invalid NOTIFY parameter"));
+        }
+        None => None,
+    };
+    let original_recipient = match parameters.value("ORCPT") {
+        Some(value) => Some(
+            OriginalRecipient::from_value(value)
+                .ok_or_else(|| Reply::new(501, (5, 5, 4), "Invalid ORCPT parameter"))?,
+        ),
+        None if parameters.contains("ORCPT") => {
+            return Err(Reply::new(501, (5, 5, 4), "Invalid ORCPT parameter"));
+        }
+        None => None,
+    };
+    Ok(Recipient {
+        mailbox,
+        notify,
+        original_recipient,
+    })
+}
+
+/// RFC 6531 section 3.7.4.1: internationalized addresses need SMTPUTF8 in `MAIL FROM`
+fn check_utf8_address(mailbox: &Mailbox, smtputf8: bool) -> Result<(), Reply> {
+    if smtputf8 || mailbox.is_ascii() {
+        Ok(())
+    } else {
+        Err(Reply::new(
+            553,
+            (5, 6, 7),
+            "Non-ASCII addresses require SMTPUTF8",
+        ))
+    }
+}
+
+fn check_known_parameters(parameters: &Parameters, known: &[&str]) -> Result<(), Reply> {
+    match parameters
+        .iter()
+        .find(|(keyword, _)| !known.contains(keyword))
+    {
+        Some((keyword, _)) => Err(Reply::new(
+            555,
+            (5, 5, 4),
+            format!("{} parameter not recognized", keyword),
+        )),
+        None => Ok(()),
+    }
+}
+
 fn handle_recipient(
     state: &mut State,
     mut parser: MessageParser,
     config: &Config,
 ) -> Future<LineResponse> {
-    futures::future::ready(if !state.ehlo_received {
-        "500 Aren't you supposed to introduce yourself? (Send EHLO)".into()
+    futures::future::ready(if state.phase == Phase::Connected {
+        Reply::new(
+            503,
+            (5, 5, 1),
+            "Aren't you supposed to introduce yourself? (Send EHLO)",
+        )
+        .into()
+    } else if state.phase == Phase::Greeted {
+        Reply::new(503, (5, 5, 1), "Need MAIL command before RCPT").into()
+    } else if state.phase == Phase::Bdat {
+        Reply::new(503, (5, 5, 1), "RCPT is not allowed after BDAT").into()
     } else {
         match parser.consume_word_until(COLON) {
             Some(word) => {
                 let word = word.to_ascii_uppercase();
                 if word == "TO" {
                     log::trace!("[MAIL] to {}", parser.remaining(),);
-                    let recipient = parser.remaining();
-                    state.recipient.push(recipient.to_owned());
-                    if state.recipient.iter().fold(0, |acc, r| acc + r.len()) > config.max_size {
-                        state.recipient.clear();
-                        "500 You're sending too much".into()
-                    } else {
-                        "250 I'll let them know".into()
+                    match parse_rcpt_to(&mut parser, state.mail_parameters.smtputf8()) {
+                        Ok(recipient) => {
+                            let size = state.recipient.iter().fold(
+                                recipient.mailbox.local.len() + recipient.mailbox.domain.len(),
+                                |acc, r| acc + r.mailbox.local.len() + r.mailbox.domain.len(),
+                            );
+                            if size > config.max_size {
+                                // RFC 5321 section 4.5.3.1.10: only this recipient is refused
+                                Reply::new(452, (4, 5, 3), "Too many recipients").into()
+                            } else {
+                                LineResponse::ValidateRecipient(recipient)
+                            }
+                        }
+                        Err(reply) => reply.into(),
                     }
                 } else {
-                    "500 Expected TO after RCPT".into()
+                    Reply::new(501, (5, 5, 4), "Expected TO after RCPT").into()
                 }
             }
-            None => "500 Expected TO after RCPT".into(),
+            None => Reply::new(501, (5, 5, 4), "Expected TO after RCPT").into(),
         }
     })
     .boxed()
 }
 
-fn handle_size(
-    _state: &mut State,
-    mut _parser: MessageParser,
-    _config: &Config,
-) -> Future<LineResponse> {
-    log::error!("TODO: SIZE {}", _parser.remaining());
-    futures::future::ready("500 Not implemented".into()).boxed()
-}
-
 fn handle_data(
     state: &mut State,
     mut _parser: MessageParser,
     _config: &Config,
 ) -> Future<LineResponse> {
-    state.is_reading_body = true;
-    futures::future::ready("354 Go on, I'm listening... (end with \\r\\n.\\r\\n)".into()).boxed()
+    futures::future::ready(match state.phase {
+        Phase::RcptTo if state.mail_parameters.body_type() == BodyType::BinaryMime => {
+            Reply::new(503, (5, 5, 1), "BODY=BINARYMIME requires BDAT").into()
+        }
+        Phase::RcptTo => {
+            state.phase = Phase::Data;
+            Reply::new(
+                354,
+                None,
+                "Go on, I'm listening... (end with \\r\\n.\\r\\n)",
+            )
+            .into()
+        }
+        Phase::MailFrom => Reply::new(
+            503,
+            (5, 5, 1),
+            "Need at least one valid recipient before DATA",
+        )
+        .into(),
+        Phase::Bdat => Reply::new(503, (5, 5, 1), "DATA is not allowed after BDAT").into(),
+        _ => Reply::new(503, (5, 5, 1), "Need MAIL command before DATA").into(),
+    })
+    .boxed()
+}
+
+/// Parses `BDAT <size> [LAST]`, see RFC 3030 section 2
+fn handle_bdat(state: &mut State, parser: MessageParser, config: &Config) -> Future<LineResponse> {
+    let mut arguments = parser.remaining().split_whitespace();
+    let size = arguments.next().and_then(|size| size.parse::<usize>().ok());
+    let last = match arguments.next() {
+        None => false,
+        Some(argument) if argument.eq_ignore_ascii_case("LAST") => true,
+        Some(_) => {
+            return futures::future::ready(
+                Reply::new(501, (5, 5, 4), "Expected BDAT <size> [LAST]").into(),
+            )
+            .boxed()
+        }
+    };
+    let size = match size {
+        Some(size) if arguments.next().is_none() => size,
+        // Without a valid size there is no way to know where the chunk ends
+        _ => {
+            return futures::future::ready(
+                Reply::new(501, (5, 5, 4), "Expected BDAT <size> [LAST]").into(),
+            )
+            .boxed()
+        }
+    };
+
+    // The size comes from the client, so the sum can overflow
+    let is_too_large = state
+        .body
+        .len()
+        .checked_add(size)
+        .map_or(true, |total| total > config.max_size);
+    futures::future::ready(match state.phase {
+        Phase::RcptTo | Phase::Bdat if is_too_large => {
+            state.reset();
+            LineResponse::DiscardChunk {
+                size,
+                reply: Reply::new(552, (5, 3, 4), "Slow it down, you're sending too much"),
+            }
+        }
+        Phase::RcptTo | Phase::Bdat => {
+            state.phase = Phase::Bdat;
+            LineResponse::ReadChunk { size, last }
+        }
+        Phase::MailFrom => LineResponse::DiscardChunk {
+            size,
+            reply: Reply::new(
+                503,
+                (5, 5, 1),
+                "Need at least one valid recipient before BDAT",
+            ),
+        },
+        _ => LineResponse::DiscardChunk {
+            size,
+            reply: Reply::new(503, (5, 5, 1), "Need MAIL command before BDAT"),
+        },
+    })
+    .boxed()
 }
 
 fn handle_verify(
@@ -286,7 +793,7 @@ fn handle_verify(
     _config: &Config,
 ) -> Future<LineResponse> {
     log::error!("TODO: VRFY {}", _parser.remaining());
-    futures::future::ready("500 Not implemented".into()).boxed()
+    futures::future::ready(Reply::new(502, (5, 5, 1), "Not implemented").into()).boxed()
 }
 
 fn handle_turn(
@@ -295,16 +802,57 @@ fn handle_turn(
     _config: &Config,
 ) -> Future<LineResponse> {
     log::error!("TODO: TURN {}", _parser.remaining());
-    futures::future::ready("500 Not implemented".into()).boxed()
+    futures::future::ready(Reply::new(502, (5, 5, 1), "Not implemented").into()).boxed()
 }
 
 fn handle_auth(
-    _state: &mut State,
-    mut _parser: MessageParser,
-    _config: &Config,
+    state: &mut State,
+    mut parser: MessageParser,
+    config: &Config,
 ) -> Future<LineResponse> {
-    log::error!("TODO: AUTH {}", _parser.remaining());
-    futures::future::ready("500 Not implemented".into()).boxed()
+    futures::future::ready(if state.phase == Phase::Connected {
+        Reply::new(
+            503,
+            (5, 5, 1),
+            "Aren't you supposed to introduce yourself? (Send EHLO)",
+        )
+        .into()
+    } else if state.authenticated_user.is_some() {
+        Reply::new(503, (5, 5, 1), "You are already authenticated").into()
+    } else if state.phase != Phase::Greeted {
+        Reply::new(
+            503,
+            (5, 5, 1),
+            "AUTH is not allowed during a mail transaction",
+        )
+        .into()
+    } else {
+        let mechanism = parser.consume_word_until(SPACE).map(str::to_owned);
+        let (mechanism, initial_response) = match mechanism {
+            Some(mechanism) => (mechanism, Some(parser.remaining())),
+            None => (parser.remaining().to_owned(), None),
+        };
+        match AuthMechanism::from_name(&mechanism) {
+            Some(mechanism) if config.auth_mechanisms().contains(&mechanism) => {
+                if state.allows_auth_mechanism(mechanism, config) {
+                    state.auth_step(auth::Exchange::start(
+                        mechanism,
+                        initial_response,
+                        &config.host,
+                    ))
+                } else {
+                    Reply::new(
+                        538,
+                        (5, 7, 11),
+                        "Encryption required for requested authentication mechanism",
+                    )
+                    .into()
+                }
+            }
+            _ => Reply::new(504, (5, 5, 4), "Unrecognized authentication type").into(),
+        }
+    })
+    .boxed()
 }
 
 fn handle_reset(
@@ -312,8 +860,17 @@ fn handle_reset(
     mut _parser: MessageParser,
     _config: &Config,
 ) -> Future<LineResponse> {
-    *state = Default::default();
-    futures::future::ready("200 It's all gone".into()).boxed()
+    state.reset();
+    futures::future::ready(Reply::new(250, (2, 0, 0), "It's all gone").into()).boxed()
+}
+
+/// RFC 5321 section 4.1.1.9: NOOP is allowed at any time, and any argument is ignored
+fn handle_noop(
+    _state: &mut State,
+    mut _parser: MessageParser,
+    _config: &Config,
+) -> Future<LineResponse> {
+    futures::future::ready(Reply::new(250, (2, 0, 0), "Ok").into()).boxed()
 }
 
 fn handle_expn(
@@ -322,7 +879,7 @@ fn handle_expn(
     _config: &Config,
 ) -> Future<LineResponse> {
     log::error!("TODO: EXPN {}", _parser.remaining());
-    futures::future::ready("500 Not implemented".into()).boxed()
+    futures::future::ready(Reply::new(502, (5, 5, 1), "Not implemented").into()).boxed()
 }
 
 fn handle_help(
@@ -331,7 +888,7 @@ fn handle_help(
     _config: &Config,
 ) -> Future<LineResponse> {
     log::error!("TODO: HELP {}", _parser.remaining());
-    futures::future::ready("500 Not implemented".into()).boxed()
+    futures::future::ready(Reply::new(502, (5, 5, 1), "Not implemented").into()).boxed()
 }
 
 fn handle_quit(
@@ -343,28 +900,264 @@ fn handle_quit(
 }
 
 const COLON: u8 = b':';
+const SPACE: u8 = b' ';
 
 impl State {
-    async fn message_received(&mut self, msg: &str, config: &Config) -> LineResponse {
-        if self.is_reading_body {
-            log::trace!("[BODY] {}", msg);
-            if msg == "." {
-                self.is_reading_body = false;
+    fn new(role: ListenerRole, is_tls: bool, session_end: Option<Instant>) -> State {
+        State {
+            is_tls,
+            is_lmtp: role == ListenerRole::Lmtp,
+            session_end,
+            ..Default::default()
+        }
+    }
+
+    /// Clears the current mail transaction, but keeps everything that is known about the
+    /// connection itself.
+    fn reset(&mut self) {
+        let phase = match self.phase {
+            Phase::Connected => Phase::Connected,
+            _ => Phase::Greeted,
+        };
+        *self = State {
+            phase,
+            is_tls: self.is_tls,
+            is_lmtp: self.is_lmtp,
+            helo_name: self.helo_name.take(),
+            authenticated_user: self.authenticated_user.take(),
+            session_end: self.session_end,
+            ..Default::default()
+        };
+    }
+
+    /// Returns true if the given line contains credentials, and should not be logged
+    fn is_authenticating(&self, line: &[u8]) -> bool {
+        self.auth_exchange.is_some()
+            || line
+                .get(..4)
+                .map(|cmd| cmd.eq_ignore_ascii_case(b"AUTH"))
+                .unwrap_or(false)
+    }
+
+    /// Mechanisms that send the password in plain text are only allowed over TLS, unless
+    /// `ConfigBuilder::allow_insecure_auth` was called
+    fn allows_auth_mechanism(&self, mechanism: AuthMechanism, config: &Config) -> bool {
+        self.is_tls || config.allow_insecure_auth || !mechanism.sends_password()
+    }
+
+    fn auth_step(&mut self, step: auth::Step) -> LineResponse {
+        match step {
+            auth::Step::Challenge(exchange, challenge) => {
+                self.auth_exchange = Some(exchange);
+                Reply::new(334, None, base64::encode(&challenge)).into()
+            }
+            auth::Step::Done(credentials) => LineResponse::Authenticate(credentials),
+            auth::Step::Lookup(lookup) => LineResponse::LookupCredentials(lookup),
+            auth::Step::Authenticated(username) => {
+                log::info!("Authenticated as {:?}", username);
+                self.authenticated_user = Some(username);
+                Reply::new(235, (2, 7, 0), "Authentication successful").into()
+            }
+            auth::Step::Rejected(username) => {
+                log::info!("Authentication failed for {:?}", username);
+                Reply::new(535, (5, 7, 8), "Authentication credentials invalid").into()
+            }
+            auth::Step::Cancelled => Reply::new(501, (5, 0, 0), "Authentication cancelled").into(),
+            auth::Step::Failed(reason) => Reply::new(501, (5, 5, 2), reason).into(),
+        }
+    }
+
+    /// Describes the current transaction to the `MailHandler`
+    fn envelope(&self, peer_addr: SocketAddr, role: ListenerRole) -> Envelope {
+        Envelope {
+            peer_addr,
+            role,
+            used_ssl: self.is_tls,
+            helo_name: self.helo_name.clone(),
+            authenticated_user: self.authenticated_user.clone(),
+            from: self.from.clone(),
+            mail_parameters: self.mail_parameters.clone(),
+            to: self.recipient.clone(),
+        }
+    }
+
+    fn hostname_validated(
+        &mut self,
+        name: String,
+        extended: bool,
+        is_valid: bool,
+        config: &Config,
+    ) -> LineResponse {
+        if !is_valid {
+            log::info!("Hostname {:?} rejected", name);
+            return Reply::new(550, (5, 7, 1), format!("{} is not welcome here", name)).into();
+        }
+        // RFC 5321 section 4.1.4: EHLO implies a RSET
+        self.reset();
+        self.phase = Phase::Greeted;
+        self.helo_name = Some(name);
+        if extended {
+            self.ehlo_reply(config).into()
+        } else {
+            Reply::new(250, None, format!("{} I'm glad to meet you", config.host)).into()
+        }
+    }
+
+    fn ehlo_reply(&self, config: &Config) -> Reply {
+        let mut cmds_to_send: Vec<Cow<'static, str>> = Vec::new();
+        cmds_to_send.push(format!("{} I'm glad to meet you", config.host).into());
+        cmds_to_send.push(format!("SIZE {}", config.max_size).into());
+        cmds_to_send.push("ENHANCEDSTATUSCODES".into());
+        cmds_to_send.push("PIPELINING".into());
+        cmds_to_send.push("8BITMIME".into());
+        cmds_to_send.push("SMTPUTF8".into());
+        cmds_to_send.push("CHUNKING".into());
+        cmds_to_send.push("BINARYMIME".into());
+        cmds_to_send.push("DSN".into());
+
+        for feature in &config.features {
+            let tag = match feature {
+                ConfigFeature::Tls if self.is_tls => continue,
+                ConfigFeature::Auth(mechanisms) => {
+                    let mechanisms: Vec<_> = mechanisms
+                        .iter()
+                        .cloned()
+                        .filter(|m| self.allows_auth_mechanism(*m, config))
+                        .collect();
+                    if mechanisms.is_empty() {
+                        continue;
+                    }
+                    ConfigFeature::Auth(mechanisms).as_ehlo_tag()
+                }
+                _ => feature.as_ehlo_tag(),
+            };
+            if let Some(tag) = tag {
+                cmds_to_send.push(tag);
+            }
+        }
+
+        // RFC 2034 section 3: the EHLO reply does not carry enhanced status codes
+        Reply::multiline(250, None, cmds_to_send)
+    }
+
+    fn sender_validated(
+        &mut self,
+        from: Option<Mailbox>,
+        parameters: Parameters,
+        decision: SenderDecision,
+    ) -> LineResponse {
+        let from_str = from.as_ref().map(ToString::to_string).unwrap_or_default();
+        match decision {
+            SenderDecision::Accept => {
+                self.from = from;
+                self.mail_parameters = parameters;
+                self.phase = Phase::MailFrom;
+                Reply::new(250, (2, 1, 0), format!("Say hi to <{}> for me", from_str)).into()
+            }
+            SenderDecision::Reject(reply) => {
+                log::info!("Sender <{}> rejected: {:?}", from_str, reply);
+                SenderDecision::rejection_reply(reply).into()
+            }
+        }
+    }
+
+    fn recipient_validated(
+        &mut self,
+        recipient: Recipient,
+        decision: RcptDecision,
+    ) -> LineResponse {
+        if decision == RcptDecision::Accept {
+            self.recipient.push(recipient);
+            self.phase = Phase::RcptTo;
+        } else {
+            log::info!("Recipient <{}> rejected: {:?}", recipient.mailbox, decision);
+        }
+        decision.reply().into()
+    }
+
+    /// How long to wait for the next line from the client
+    fn line_timeout(&self, config: &Config) -> Duration {
+        let timeout = if self.is_receiving_message() {
+            config.data_timeout
+        } else if self.phase == Phase::Connected {
+            config.greeting_timeout
+        } else {
+            config.command_timeout
+        };
+        self.session_limit(timeout)
+    }
+
+    /// Shortens `timeout` so it does not run past `Config::session_timeout`
+    fn session_limit(&self, timeout: Duration) -> Duration {
+        match self.session_end {
+            Some(session_end) => {
+                let now = Instant::now();
+                let remaining = if session_end > now {
+                    session_end - now
+                } else {
+                    Duration::from_secs(0)
+                };
+                cmp::min(timeout, remaining)
+            }
+            None => timeout,
+        }
+    }
+
+    /// True while the lines of a `DATA` message or the chunks of `BDAT` are received
+    fn is_receiving_message(&self) -> bool {
+        self.phase == Phase::Data || self.phase == Phase::Bdat
+    }
+
+    /// Handles a line of the message body. These are kept as bytes, because the body does not
+    /// have to be valid UTF-8.
+    fn body_line_received(&mut self, line: &[u8], config: &Config) -> LineResponse {
+        if line == b"." {
+            if self.is_body_too_large {
+                self.reset();
+                Reply::new(552, (5, 3, 4), "Slow it down, you're sending too much").into()
+            } else {
                 LineResponse::Done
+            }
+        } else if self.is_body_too_large {
+            // The rest of the message is discarded, the client only gets a reply after the
+            // final "."
+            LineResponse::None
+        } else {
+            // RFC 5321 section 4.5.2: the client added a dot to every line that started
+            // with one, so the first character of such a line has to be removed
+            let line = if line.starts_with(b".") {
+                &line[1..]
             } else {
-                self.body += msg;
-                self.body += "\r\n";
-                if self.body.bytes().len() >= config.max_size {
-                    self.body.clear();
-                    "500 Slow it down, you're sending too much".into()
-                } else {
-                    LineResponse::None
-                }
+                line
+            };
+            if self.body.len() + line.len() + 2 > config.max_size {
+                self.body.clear();
+                self.is_body_too_large = true;
+            } else {
+                self.body.extend_from_slice(line);
+                self.body.extend_from_slice(b"\r\n");
             }
+            LineResponse::None
+        }
+    }
+
+    async fn message_received(&mut self, line: &[u8], config: &Config) -> LineResponse {
+        if self.phase == Phase::Data {
+            return self.body_line_received(line, config);
+        }
+        // Commands are always ASCII, except for addresses and parameters with SMTPUTF8
+        let msg = String::from_utf8_lossy(line);
+        let msg: &str = &msg;
+        if let Some(exchange) = self.auth_exchange.take() {
+            self.auth_step(exchange.respond(msg))
         } else if msg.get(..8).map(|m| m.to_ascii_uppercase()) == Some(String::from("STARTTLS"))
             && config.features.contains(&ConfigFeature::Tls)
         {
-            LineResponse::Upgrade
+            if self.is_tls {
+                Reply::new(503, (5, 5, 1), "TLS is already active").into()
+            } else {
+                LineResponse::Upgrade
+            }
         } else if let Some(chars) = msg.get(..4) {
             let upper_case = chars.to_ascii_uppercase();
             let bytes: &[u8; 4] = arrayref::array_ref![upper_case.as_bytes(), 0, 4];
@@ -373,20 +1166,22 @@ impl State {
                 cmd(self, parser, config).await
             } else {
                 log::error!("Client send an unknown command: {:?}", &msg[..]);
-                "500 Unknown command".into()
+                Reply::new(500, (5, 5, 2), "Unknown command").into()
             }
         } else {
             const MAX_MSG_LEN: usize = 20;
-            if msg.len() > MAX_MSG_LEN {
+            if msg.chars().count() > MAX_MSG_LEN {
+                // Cut at a character, the line can contain UTF-8
+                let start: String = msg.chars().take(MAX_MSG_LEN).collect();
                 log::debug!(
                     "Unknown client command: \"{}...\" (first {} chars shown)",
-                    &msg[..MAX_MSG_LEN],
+                    start,
                     MAX_MSG_LEN
                 );
             } else {
                 log::debug!("Unknown client command: \"{}\"", msg);
             }
-            "500 Unknown command".into()
+            Reply::new(500, (5, 5, 2), "Unknown command").into()
         }
     }
 }
@@ -394,22 +1189,133 @@ impl State {
 #[derive(Debug)]
 enum LineResponse {
     None,
-    ReplyWith(Cow<'static, str>),
-    ReplyWithMultiple(Vec<Cow<'static, str>>),
+    Reply(Reply),
+    /// A `BDAT` chunk of `size` bytes follows, which is part of the message
+    ReadChunk {
+        size: usize,
+        last: bool,
+    },
+    /// A `BDAT` chunk of `size` bytes follows, but it was rejected with `reply`
+    DiscardChunk {
+        size: usize,
+        reply: Reply,
+    },
     Upgrade,
+    Authenticate(Credentials),
+    /// `EHLO` (`extended`) or `HELO` was received, and the `MailHandler` has to decide if the
+    /// hostname is accepted
+    ValidateHostname {
+        name: String,
+        extended: bool,
+    },
+    /// `MAIL FROM` was parsed, and the `MailHandler` has to decide if it is accepted
+    ValidateSender {
+        from: Option<Mailbox>,
+        parameters: Parameters,
+    },
+    /// `RCPT TO` was parsed, and the `MailHandler` has to decide if it is accepted
+    ValidateRecipient(Recipient),
+    LookupCredentials(auth::Lookup),
     Done,
     Quit,
     // Err(failure::Error),
 }
 
-impl From<String> for LineResponse {
-    fn from(s: String) -> LineResponse {
-        LineResponse::ReplyWith(s.into())
+impl From<Reply> for LineResponse {
+    fn from(reply: Reply) -> LineResponse {
+        LineResponse::Reply(reply)
     }
 }
 
-impl From<&'static str> for LineResponse {
-    fn from(s: &'static str) -> LineResponse {
-        LineResponse::ReplyWith(s.into())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::io::Cursor;
+
+    fn receiving_state() -> State {
+        State {
+            phase: Phase::Data,
+            ..Default::default()
+        }
+    }
+
+    fn config(max_size_kb: usize) -> Config {
+        Config::build("localhost")
+            .max_message_size_kb(max_size_kb)
+            .build()
+    }
+
+    fn receive(state: &mut State, lines: &[&[u8]], config: &Config) {
+        for line in lines {
+            match state.body_line_received(line, config) {
+                LineResponse::None => {}
+                other => panic!("Unexpected response to {:?}: {:?}", line, other),
+            }
+        }
+    }
+
+    #[test]
+    fn body_lines_are_unstuffed() {
+        let config = config(1);
+        let mut state = receiving_state();
+        receive(&mut state, &[b"..", b".foo", b"bar", b"...", b""], &config);
+        assert_eq!(state.body, b".\r\nfoo\r\nbar\r\n..\r\n\r\n".to_vec());
+    }
+
+    #[test]
+    fn lone_dot_ends_the_body() {
+        let config = config(1);
+        let mut state = receiving_state();
+        receive(&mut state, &[b"Subject: test", b""], &config);
+        match state.body_line_received(b".", &config) {
+            LineResponse::Done => {}
+            other => panic!("Expected the end of the message, got {:?}", other),
+        }
+        assert_eq!(state.body, b"Subject: test\r\n\r\n".to_vec());
+    }
+
+    #[test]
+    fn bare_lf_lines_end_with_crlf_in_the_body() {
+        let config = config(1);
+        let mut state = receiving_state();
+        let input = Cursor::new(b"first\nsecond\r\n.third\n".to_vec());
+        let mut reader = LineReader::new(input, 1024, log::Level::Trace);
+        for _ in 0..3 {
+            let line = block_on(reader.next()).unwrap().unwrap();
+            receive(&mut state, &[&line], &config);
+        }
+        assert_eq!(state.body, b"first\r\nsecond\r\nthird\r\n".to_vec());
+    }
+
+    #[test]
+    fn too_large_body_is_rejected_after_the_final_dot() {
+        let config = config(1);
+        let mut state = receiving_state();
+        let line = vec![b'a'; 600];
+        receive(&mut state, &[&line, &line, b"", &line], &config);
+        assert!(state.body.is_empty());
+        match state.body_line_received(b".", &config) {
+            LineResponse::Reply(reply) => {
+                assert_eq!(reply.code, 552);
+                assert_eq!(reply.enhanced, Some((5, 3, 4)));
+            }
+            other => panic!("Expected a 552 reply, got {:?}", other),
+        }
+        assert_eq!(state.phase, Phase::Greeted);
+        assert!(!state.is_body_too_large);
+    }
+
+    #[test]
+    fn body_up_to_the_limit_is_accepted() {
+        let config = config(1);
+        let mut state = receiving_state();
+        let line = vec![b'a'; 1022];
+        receive(&mut state, &[&line], &config);
+        assert_eq!(state.body.len(), 1024);
+        match state.body_line_received(b".", &config) {
+            LineResponse::Done => {}
+            other => panic!("Expected the end of the message, got {:?}", other),
+        }
     }
 }
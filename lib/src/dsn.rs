@@ -0,0 +1,157 @@
+//! The parameters of the Delivery Status Notification extension, as defined in RFC 3461
+
+/// What a bounce should contain, set with `RET=` on `MAIL FROM`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DsnReturn {
+    /// `RET=FULL`, the whole message
+    Full,
+    /// `RET=HDRS`, only the headers
+    Headers,
+}
+
+impl DsnReturn {
+    pub(crate) fn from_value(value: &str) -> Option<DsnReturn> {
+        if value.eq_ignore_ascii_case("FULL") {
+            Some(DsnReturn::Full)
+        } else if value.eq_ignore_ascii_case("HDRS") {
+            Some(DsnReturn::Headers)
+        } else {
+            None
+        }
+    }
+}
+
+/// When a notification should be sent for a recipient, set with `NOTIFY=` on `RCPT TO`.
+/// Everything is false for `NOTIFY=NEVER`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Notify {
+    pub success: bool,
+    pub failure: bool,
+    pub delay: bool,
+}
+
+impl Notify {
+    /// Parses `"NEVER" / 1#( "SUCCESS" / "FAILURE" / "DELAY" )`
+    pub(crate) fn from_value(value: &str) -> Option<Notify> {
+        if value.eq_ignore_ascii_case("NEVER") {
+            return Some(Notify::default());
+        }
+        let mut notify = Notify::default();
+        for keyword in value.split(',') {
+            if keyword.eq_ignore_ascii_case("SUCCESS") {
+                notify.success = true;
+            } else if keyword.eq_ignore_ascii_case("FAILURE") {
+                notify.failure = true;
+            } else if keyword.eq_ignore_ascii_case("DELAY") {
+                notify.delay = true;
+            } else {
+                return None;
+            }
+        }
+        Some(notify)
+    }
+}
+
+/// The recipient as the original sender specified it, set with `ORCPT=` on `RCPT TO`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OriginalRecipient {
+    /// The address type, usually `rfc822`
+    pub address_type: String,
+    /// The address, with the xtext encoding removed
+    pub address: String,
+}
+
+impl OriginalRecipient {
+    /// Parses `addr-type ";" xtext`
+    pub(crate) fn from_value(value: &str) -> Option<OriginalRecipient> {
+        let index = value.find(';')?;
+        let address_type = &value[..index];
+        if address_type.is_empty() {
+            return None;
+        }
+        Some(OriginalRecipient {
+            address_type: address_type.to_owned(),
+            address: decode_xtext(&value[index + 1..])?,
+        })
+    }
+}
+
+/// Removes the `+XX` encoding of RFC 3461 section 4. Returns `None` for malformed xtext, or
+/// if the result is not UTF-8.
+pub(crate) fn decode_xtext(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'+' {
+            let hex = [iter.next()?, iter.next()?];
+            // Only upper case hex digits, `from_str_radix` would also accept a sign
+            if !hex
+                .iter()
+                .all(|b| b.is_ascii_digit() || (b'A'..=b'F').contains(b))
+            {
+                return None;
+            }
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xtext_is_decoded() {
+        assert_eq!(decode_xtext("plain"), Some("plain".to_owned()));
+        assert_eq!(decode_xtext(""), Some(String::new()));
+        assert_eq!(decode_xtext("a+2Bb+3Dc+20"), Some("a+b=c ".to_owned()));
+        assert_eq!(decode_xtext("+C3+A4"), Some("ä".to_owned()));
+    }
+
+    #[test]
+    fn malformed_xtext_is_rejected() {
+        // Lower case hex digits
+        assert_eq!(decode_xtext("+2b"), None);
+        // Incomplete escapes
+        assert_eq!(decode_xtext("abc+"), None);
+        assert_eq!(decode_xtext("abc+2"), None);
+        // Not hex at all, including signs
+        assert_eq!(decode_xtext("+GG"), None);
+        assert_eq!(decode_xtext("++1"), None);
+        assert_eq!(decode_xtext("+-1"), None);
+        // Not UTF-8 after decoding
+        assert_eq!(decode_xtext("+FF"), None);
+    }
+
+    #[test]
+    fn notify_values() {
+        assert_eq!(Notify::from_value("never"), Some(Notify::default()));
+        assert_eq!(
+            Notify::from_value("SUCCESS,DELAY"),
+            Some(Notify {
+                success: true,
+                failure: false,
+                delay: true,
+            })
+        );
+        assert_eq!(Notify::from_value("SUCCESS,"), None);
+        assert_eq!(Notify::from_value("ALWAYS"), None);
+    }
+
+    #[test]
+    fn original_recipient() {
+        assert_eq!(
+            OriginalRecipient::from_value("rfc822;user+2Btag@example.com"),
+            Some(OriginalRecipient {
+                address_type: "rfc822".to_owned(),
+                address: "user+tag@example.com".to_owned(),
+            })
+        );
+        assert_eq!(OriginalRecipient::from_value(";user@example.com"), None);
+        assert_eq!(OriginalRecipient::from_value("user@example.com"), None);
+    }
+}
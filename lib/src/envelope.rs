@@ -0,0 +1,93 @@
+use crate::address::{Mailbox, Recipient};
+use crate::config::ListenerRole;
+use crate::parameters::Parameters;
+use crate::reply::Reply;
+use std::net::SocketAddr;
+
+/// Everything that is known about a mail transaction before the message itself is received
+#[derive(Clone, Debug)]
+pub struct Envelope {
+    pub peer_addr: SocketAddr,
+    pub role: ListenerRole,
+    pub used_ssl: bool,
+    /// The name the client introduced itself with in `EHLO` or `HELO`, a domain or an address
+    /// literal like `[127.0.0.1]`
+    pub helo_name: Option<String>,
+    /// The username the client logged in with, if it sent `AUTH`
+    pub authenticated_user: Option<String>,
+    /// The reverse-path of `MAIL FROM`. This is `None` for the null reverse-path `<>`.
+    pub from: Option<Mailbox>,
+    /// The ESMTP parameters of `MAIL FROM`
+    pub mail_parameters: Parameters,
+    /// The recipients that were accepted so far
+    pub to: Vec<Recipient>,
+}
+
+/// The answer to `MAIL FROM`, returned by `MailHandler::validate_sender`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SenderDecision {
+    /// 250, the sender is accepted
+    Accept,
+    /// The sender is refused with the given reply, which should be a 4xx or 5xx reply
+    Reject(Reply),
+}
+
+/// The answer to `RCPT TO`, returned by `MailHandler::validate_recipient`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RcptDecision {
+    /// 250, the recipient is accepted
+    Accept,
+    /// 450, the mailbox is temporarily unavailable, e.g. because it is locked or full
+    TryLater,
+    /// 451, the recipient could not be checked, e.g. because a database is down
+    LocalError,
+    /// 550, there is no such user
+    NoSuchUser,
+    /// 551, the user is not local. If the new address is known, it is included in the reply.
+    UserNotLocal(Option<Mailbox>),
+}
+
+impl SenderDecision {
+    /// The reply of `Reject` is built by the handler. Anything but a 4xx or 5xx reply is replaced
+    /// with 550, so the client does not think the sender was accepted. Like
+    /// `Rejection::reply`, an enhanced status code of the wrong class is left out.
+    pub(crate) fn rejection_reply(reply: Reply) -> Reply {
+        let code = match reply.code {
+            400..=599 => reply.code,
+            _ => 550,
+        };
+        let enhanced = reply
+            .enhanced
+            .filter(|(class, _, _)| u16::from(*class) == code / 100);
+        Reply {
+            code,
+            enhanced,
+            ..reply
+        }
+    }
+}
+
+impl RcptDecision {
+    pub(crate) fn reply(&self) -> Reply {
+        match self {
+            RcptDecision::Accept => Reply::new(250, (2, 1, 5), "I'll let them know"),
+            RcptDecision::TryLater => Reply::new(
+                450,
+                (4, 2, 1),
+                "Mailbox temporarily unavailable, try again later",
+            ),
+            RcptDecision::LocalError => Reply::new(
+                451,
+                (4, 3, 0),
+                "Could not verify the recipient, try again later",
+            ),
+            RcptDecision::NoSuchUser => Reply::new(550, (5, 1, 1), "No such user here"),
+            RcptDecision::UserNotLocal(Some(forward)) => Reply::new(
+                551,
+                (5, 1, 6),
+                format!("User not local; please try <{}>", forward),
+            ),
+            RcptDecision::UserNotLocal(None) => Reply::new(551, (5, 1, 6), "User not local"),
+        }
+    }
+}
@@ -11,86 +11,310 @@ pub extern crate mailparse;
 
 #[macro_use]
 mod tcp_stream_helper;
+mod address;
+mod auth;
 mod collector;
 mod config;
 mod connection;
+mod dsn;
+mod envelope;
 mod line_reader;
 mod message_parser;
+mod parameters;
+mod reply;
+mod shutdown;
+mod tls_stream;
+mod verdict;
 
+pub use crate::address::{Mailbox, Recipient};
+pub use crate::auth::{AuthMechanism, Credentials, ScramCredentials, StoredCredentials};
 pub use crate::collector::Email;
-pub use crate::config::{Config, ConfigFeature};
+pub use crate::config::{Config, ConfigFeature, ListenerRole};
+pub use crate::dsn::{DsnReturn, Notify, OriginalRecipient};
+pub use crate::envelope::{Envelope, RcptDecision, SenderDecision};
+pub use crate::parameters::{BodyType, Parameters};
+pub use crate::reply::Reply;
+pub use crate::shutdown::ServerHandle;
+pub use crate::verdict::{Accepted, Rejection, Verdict};
 
 use crate::collector::Collector;
-use futures::{FutureExt, TryStreamExt};
+use crate::shutdown::ShutdownSignal;
+use failure::ResultExt;
+use futures::channel::oneshot;
+use futures::future::Either;
+use futures::{FutureExt, StreamExt, TryStreamExt};
 use runtime::net::TcpListener;
+use std::net::SocketAddr;
 use std::pin::Pin;
 
 type Future<T> = Pin<Box<dyn std::future::Future<Output = T> + Send>>;
 
 pub trait MailHandler: Send {
-    fn handle_mail(&mut self, mail: Email) -> bool;
+    /// Take over a received message. Returning a temporary `Rejection` makes the client try
+    /// again later, so nothing is lost if the message can not be stored right now.
+    fn handle_mail(&mut self, mail: Email) -> Verdict;
+    /// Like `handle_mail`, but with one verdict per entry in `mail.to`, e.g. because one of
+    /// the mailboxes is over quota. On `ListenerRole::Lmtp` listeners every recipient gets its
+    /// own reply. Otherwise the message is accepted if any recipient accepted it, and the
+    /// handler is responsible for bouncing it for the others. By default `handle_mail` decides
+    /// for all recipients.
+    fn handle_mail_per_recipient(&mut self, mail: Email) -> Vec<Verdict> {
+        let recipients = mail.to.len();
+        vec![self.handle_mail(mail); recipients]
+    }
+    /// Decide what happens to a message that is not valid MIME, so it can not be passed to
+    /// `handle_mail`. `raw_body` is the message as the client sent it. By default the message is
+    /// permanently rejected.
+    fn handle_unparseable(&mut self, _envelope: &Envelope, _raw_body: &[u8]) -> Verdict {
+        Err(Rejection::permanent("The message could not be parsed"))
+    }
+    /// Decide whether a client that introduces itself as `hostname` in `EHLO` or `HELO` is
+    /// accepted. Rejected clients get a 550 reply. By default every hostname is accepted.
     fn validate_hostname(&mut self, _hostname: &str) -> bool {
         true
     }
+    /// Check the credentials of a client that sent `AUTH`. By default every login is refused.
+    fn authenticate(&mut self, _credentials: &Credentials) -> bool {
+        false
+    }
+    /// Look up what is needed to verify `CRAM-MD5` or `SCRAM-SHA-256`. By default no user
+    /// exists.
+    fn lookup_credentials(
+        &mut self,
+        _mechanism: AuthMechanism,
+        _username: &str,
+    ) -> Option<StoredCredentials> {
+        None
+    }
+    /// Decide whether the sender of `MAIL FROM` is accepted. `envelope.from` is the sender that
+    /// is checked. By default every sender is accepted.
+    fn validate_sender(&mut self, _envelope: &Envelope) -> SenderDecision {
+        SenderDecision::Accept
+    }
+    /// Decide whether mail for `recipient` is accepted, while the client sends `RCPT TO`.
+    /// By default every recipient is accepted.
+    fn validate_recipient(&mut self, _envelope: &Envelope, _recipient: &Mailbox) -> RcptDecision {
+        RcptDecision::Accept
+    }
 }
 
 pub trait MailHandlerAsync: Send {
-    fn handle_mail_async(&mut self, mail: Email) -> Future<bool>;
+    /// True for the implementation of every `MailHandler`, which blocks while it creates its
+    /// futures. Those handlers get their own thread, so they do not hold up the runtime.
+    #[doc(hidden)]
+    fn is_blocking(&self) -> bool {
+        false
+    }
+    fn handle_mail_async(&mut self, mail: Email) -> Future<Verdict>;
+    fn handle_mail_per_recipient_async(&mut self, mail: Email) -> Future<Vec<Verdict>> {
+        let recipients = mail.to.len();
+        self.handle_mail_async(mail)
+            .map(move |verdict| vec![verdict; recipients])
+            .boxed()
+    }
+    fn handle_unparseable(&mut self, _envelope: Envelope, _raw_body: Vec<u8>) -> Future<Verdict> {
+        futures::future::ready(Err(Rejection::permanent("The message could not be parsed"))).boxed()
+    }
     fn validate_hostname(&mut self, _hostname: &str) -> Future<bool> {
         futures::future::ready(true).boxed()
     }
+    fn authenticate(&mut self, _credentials: Credentials) -> Future<bool> {
+        futures::future::ready(false).boxed()
+    }
+    fn lookup_credentials(
+        &mut self,
+        _mechanism: AuthMechanism,
+        _username: String,
+    ) -> Future<Option<StoredCredentials>> {
+        futures::future::ready(None).boxed()
+    }
+    fn validate_sender(&mut self, _envelope: Envelope) -> Future<SenderDecision> {
+        futures::future::ready(SenderDecision::Accept).boxed()
+    }
+    fn validate_recipient(
+        &mut self,
+        _envelope: Envelope,
+        _recipient: Mailbox,
+    ) -> Future<RcptDecision> {
+        futures::future::ready(RcptDecision::Accept).boxed()
+    }
 }
 
 impl<T> MailHandlerAsync for T
 where
     T: MailHandler,
 {
-    fn handle_mail_async(&mut self, mail: Email) -> Future<bool> {
+    fn is_blocking(&self) -> bool {
+        true
+    }
+
+    fn handle_mail_async(&mut self, mail: Email) -> Future<Verdict> {
         let result = self.handle_mail(mail);
         futures::future::ready(result).boxed()
     }
 
+    fn handle_mail_per_recipient_async(&mut self, mail: Email) -> Future<Vec<Verdict>> {
+        let result = self.handle_mail_per_recipient(mail);
+        futures::future::ready(result).boxed()
+    }
+
+    fn handle_unparseable(&mut self, envelope: Envelope, raw_body: Vec<u8>) -> Future<Verdict> {
+        let result = MailHandler::handle_unparseable(self, &envelope, &raw_body);
+        futures::future::ready(result).boxed()
+    }
+
     fn validate_hostname(&mut self, hostname: &str) -> Future<bool> {
-        let result = self.validate_hostname(hostname);
+        let result = MailHandler::validate_hostname(self, hostname);
+        futures::future::ready(result).boxed()
+    }
+
+    fn authenticate(&mut self, credentials: Credentials) -> Future<bool> {
+        let result = MailHandler::authenticate(self, &credentials);
+        futures::future::ready(result).boxed()
+    }
+
+    fn lookup_credentials(
+        &mut self,
+        mechanism: AuthMechanism,
+        username: String,
+    ) -> Future<Option<StoredCredentials>> {
+        let result = MailHandler::lookup_credentials(self, mechanism, &username);
+        futures::future::ready(result).boxed()
+    }
+
+    fn validate_sender(&mut self, envelope: Envelope) -> Future<SenderDecision> {
+        let result = MailHandler::validate_sender(self, &envelope);
+        futures::future::ready(result).boxed()
+    }
+
+    fn validate_recipient(
+        &mut self,
+        envelope: Envelope,
+        recipient: Mailbox,
+    ) -> Future<RcptDecision> {
+        let result = MailHandler::validate_recipient(self, &envelope, &recipient);
         futures::future::ready(result).boxed()
     }
 }
 
-pub async fn spawn(
+/// Starts the server. It runs until `ServerHandle::shutdown` is called, or an error occurs.
+pub fn spawn(
     config: Config,
     handler: impl MailHandlerAsync + 'static,
-) -> Result<(), failure::Error> {
-    let (collector_future, collector) = Collector::spawn(handler).await;
+) -> (ServerHandle, Future<Result<(), failure::Error>>) {
+    spawn_pool(config, vec![handler])
+}
 
-    let tcp_future = spawn_tcp(config, collector);
+/// Like `spawn`, but with a pool of handlers, e.g. one for every database connection. Every
+/// handler handles one request at a time, so the pool size is the number of messages that are
+/// handled concurrently. Every `MailHandler` runs on its own thread, so a handler that blocks,
+/// like a synchronous database call, only holds up its own requests.
+pub fn spawn_pool(
+    config: Config,
+    handlers: Vec<impl MailHandlerAsync + 'static>,
+) -> (ServerHandle, Future<Result<(), failure::Error>>) {
+    let (handle, signal, local_addrs) = shutdown::channel();
+    let fut = async move {
+        let (collector_future, collector) = Collector::spawn(handlers, &config).await?;
 
-    futures::future::try_join(collector_future, tcp_future).await?;
-    Ok(())
+        let tcp_future = spawn_tcp(config, collector, signal.clone(), local_addrs);
+
+        let server = futures::future::try_join(collector_future, tcp_future).boxed();
+        let deadline = signal.wait().then(runtime::time::Delay::new).boxed();
+        match futures::future::select(server, deadline).await {
+            Either::Left((result, _)) => {
+                result?;
+            }
+            Either::Right(_) => {
+                log::warn!("The shutdown grace period is over, not waiting for busy handlers")
+            }
+        }
+        Ok(())
+    };
+    (handle, fut.boxed())
 }
 
-async fn spawn_tcp(config: Config, collector: Collector) -> Result<(), failure::Error> {
-    let mut streams = vec![];
-    streams.push(TcpListener::bind((std::net::Ipv4Addr::UNSPECIFIED, 25))?);
+async fn spawn_tcp(
+    config: Config,
+    collector: Collector,
+    shutdown: ShutdownSignal,
+    local_addrs: oneshot::Sender<Vec<SocketAddr>>,
+) -> Result<(), failure::Error> {
+    let mut streams = Vec::with_capacity(config.listeners.len());
+    let mut addrs = Vec::with_capacity(config.listeners.len());
+    for listener in &config.listeners {
+        if listener.role == ListenerRole::ImplicitTls && config.tls_acceptor.is_none() {
+            failure::bail!(
+                "Could not listen on {}: implicit TLS requires TLS to be configured",
+                listener.addr
+            );
+        }
+        let stream = TcpListener::bind(listener.addr)
+            .with_context(|e| format!("Could not listen on {}: {:?}", listener.addr, e))?;
+        let addr = stream.local_addr().unwrap_or(listener.addr);
+        log::info!("Listening on {} ({:?})", addr, listener.role);
+        streams.push((stream, listener.role));
+        addrs.push(addr);
+    }
+    let _ = local_addrs.send(addrs);
+
+    // The stream owns the listeners, so they are closed as soon as it ends
+    let accept = futures::stream::select_all(streams.into_iter().map(|(listener, role)| {
+        Box::pin(futures::stream::unfold(
+            listener,
+            move |mut listener| async move {
+                let client = listener
+                    .accept()
+                    .await
+                    .map(|(client, _)| (client, role))
+                    .map_err(failure::Error::from);
+                Some((client, listener))
+            },
+        ))
+    }));
 
-    let select = futures::stream::select_all(
-        streams
-            .iter_mut()
-            .map(|s| s.incoming().map_err(failure::Error::from)),
-    );
-    log::info!("Listening on port 25");
+    // New connections are refused once the server shuts down, and the listeners are dropped,
+    // so clients do not wait in the backlog. The sessions that are still open are waited for.
+    let stop = Box::pin(shutdown.wait());
+    let clients = futures::stream::unfold((accept, stop), |(mut accept, stop)| async move {
+        let next = match futures::future::select(accept.next(), stop).await {
+            Either::Left((client, stop)) => Some((client?, stop)),
+            Either::Right(_) => None,
+        };
+        match next {
+            Some((client, stop)) => Some((client, (accept, stop))),
+            None => {
+                drop(accept);
+                log::info!("Shutting down, no longer accepting connections");
+                None
+            }
+        }
+    });
 
-    select
-        .try_for_each_concurrent(None, |client| {
+    clients
+        .try_for_each_concurrent(None, |(client, role)| {
             let config = config.clone();
             let collector = collector.clone();
+            let shutdown = shutdown.clone();
             runtime::spawn(async move {
                 let peer_addr = client.peer_addr()?;
                 let local_port = client.local_addr().map(|a| a.port()).unwrap_or(0);
-                log::info!("Received client {:?} on port {}", peer_addr, local_port);
-                if let Err(e) =
-                    crate::connection::run(client, collector.clone(), config.clone()).await
-                {
-                    log::error!("Client error: {:?}", e);
+                log::info!(
+                    "Received client {:?} on port {} ({:?})",
+                    peer_addr,
+                    local_port,
+                    role
+                );
+                // Dropping the session after the grace period closes its connection
+                let deadline = shutdown.wait().then(runtime::time::Delay::new).boxed();
+                let session = crate::connection::run(client, collector, config, role, shutdown);
+                match futures::future::select(session.boxed(), deadline).await {
+                    Either::Left((Ok(()), _)) => {}
+                    Either::Left((Err(e), _)) => log::error!("Client error: {:?}", e),
+                    Either::Right(_) => log::warn!(
+                        "Closing the connection of {:?}, the shutdown grace period is over",
+                        peer_addr
+                    ),
                 }
                 log::info!("Client {:?} done", peer_addr);
                 Ok(())
@@ -102,5 +326,70 @@ async fn spawn_tcp(config: Config, collector: Collector) -> Result<(), failure::
 }
 
 pub fn run(config: Config, handler: impl MailHandlerAsync + 'static) -> failure::Error {
-    futures::executor::block_on(spawn(config, handler)).unwrap_err()
+    // Without the handle the server is never shut down, so it only stops on an error
+    let (_, server) = spawn(config, handler);
+    futures::executor::block_on(server).unwrap_err()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::net::{Ipv4Addr, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    struct Handler;
+
+    impl MailHandler for Handler {
+        fn handle_mail(&mut self, _mail: Email) -> Verdict {
+            Ok(Accepted::default())
+        }
+    }
+
+    /// Sends every command, and returns the greeting and the first line of every reply
+    fn send_commands(addr: SocketAddr, commands: &[&str]) -> io::Result<Vec<String>> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut replies = Vec::new();
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        replies.push(line.trim_end().to_owned());
+        for command in commands {
+            write!(stream, "{}\r\n", command)?;
+            line.clear();
+            reader.read_line(&mut line)?;
+            replies.push(line.trim_end().to_owned());
+        }
+        Ok(replies)
+    }
+
+    #[runtime::test]
+    async fn listens_on_the_configured_address() {
+        let config = Config::build("localhost")
+            .listen((Ipv4Addr::LOCALHOST, 0), ListenerRole::Relay)
+            .build();
+        let (handle, server) = spawn(config, Handler);
+        let server = runtime::spawn(server);
+        // The OS picks the port, which is known once the listener is bound
+        let addrs = handle.local_addrs().await;
+        assert_eq!(addrs.len(), 1, "{:?}", addrs);
+        let addr = addrs[0];
+        assert_eq!(addr.ip(), Ipv4Addr::LOCALHOST);
+        assert_ne!(addr.port(), 0);
+
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        thread::spawn(move || {
+            let _ = sender.send(send_commands(addr, &["NOOP", "QUIT"]));
+        });
+        let replies = receiver.await.unwrap().unwrap();
+        assert!(replies[0].starts_with("220 "), "{:?}", replies);
+        assert!(replies[1].starts_with("250 "), "{:?}", replies);
+        assert!(replies[2].starts_with("221 "), "{:?}", replies);
+
+        handle.shutdown(Duration::from_secs(1));
+        server.await.unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
 }
@@ -1,6 +1,7 @@
-use futures::io::{AsyncRead, AsyncWrite};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 use futures::ready;
 use futures::Stream;
+use std::cmp;
 use std::collections::VecDeque;
 use std::pin::Pin;
 use std::task::{Context, Poll};
@@ -8,19 +9,81 @@ use std::task::{Context, Poll};
 pub struct LineReader<R: AsyncRead + AsyncWrite + Unpin> {
     inner: R,
     max_size: usize,
+    log_level: log::Level,
     read_buffer: VecDeque<u8>,
     write_buffer: VecDeque<u8>,
 }
 
 impl<R: AsyncRead + AsyncWrite + Unpin> LineReader<R> {
-    pub fn new(inner: R, max_size: usize) -> Self {
+    pub fn new(inner: R, max_size: usize, log_level: log::Level) -> Self {
         Self {
             inner,
             max_size,
+            log_level,
             read_buffer: Default::default(),
             write_buffer: Default::default(),
         }
     }
+
+    /// The level the lines that are sent and received are logged at
+    pub fn log_level(&self) -> log::Level {
+        self.log_level
+    }
+
+    /// Returns the underlying stream. Any data that was received but not read yet is discarded.
+    pub fn into_inner(self) -> R {
+        if !self.read_buffer.is_empty() {
+            log::debug!(
+                "Discarding {} unread bytes from the line reader",
+                self.read_buffer.len()
+            );
+        }
+        self.inner
+    }
+
+    /// Adds a line and its CRLF to the write buffer, without sending it yet. The buffer is sent
+    /// when the reader is flushed.
+    pub fn queue_line(&mut self, line: &[u8]) {
+        self.write_buffer.extend(line);
+        self.write_buffer.extend(b"\r\n");
+    }
+
+    /// Returns true if a complete line was received, but not read yet
+    pub fn has_buffered_line(&self) -> bool {
+        self.read_buffer.contains(&b'\n')
+    }
+
+    /// Reads exactly `count` bytes without splitting them into lines, as needed for `BDAT`.
+    /// The bytes are appended to `output`, or thrown away if `output` is `None`.
+    pub async fn read_chunk(
+        &mut self,
+        mut count: usize,
+        mut output: Option<&mut Vec<u8>>,
+    ) -> Result<(), std::io::Error> {
+        let buffered = cmp::min(count, self.read_buffer.len());
+        let bytes = self.read_buffer.drain(..buffered);
+        if let Some(output) = output.as_mut() {
+            output.extend(bytes);
+        }
+        count -= buffered;
+
+        let mut buffer = [0u8; 4096];
+        while count > 0 {
+            let length = cmp::min(count, buffer.len());
+            let read = self.inner.read(&mut buffer[..length]).await?;
+            if read == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Connection closed in the middle of a chunk",
+                ));
+            }
+            if let Some(output) = output.as_mut() {
+                output.extend_from_slice(&buffer[..read]);
+            }
+            count -= read;
+        }
+        Ok(())
+    }
 }
 
 impl<R: AsyncRead + AsyncWrite + Unpin> LineReader<R> {
@@ -54,7 +117,9 @@ impl<R: AsyncRead + AsyncWrite + Unpin> LineReader<R> {
 }
 
 impl<R: AsyncRead + AsyncWrite + Unpin> Stream for LineReader<R> {
-    type Item = std::result::Result<String, std::io::Error>;
+    /// Lines are returned without the line ending. They are not decoded, because message
+    /// bodies can contain 8-bit data that is not UTF-8.
+    type Item = std::result::Result<Vec<u8>, std::io::Error>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this: &mut Self = Pin::into_inner(self);
@@ -71,7 +136,6 @@ impl<R: AsyncRead + AsyncWrite + Unpin> Stream for LineReader<R> {
                 if line.last() == Some(&b'\r') {
                     line.pop();
                 }
-                let line = String::from_utf8_lossy(&line).into_owned();
                 return Poll::Ready(Some(Ok(line)));
             }
 
@@ -1,15 +1,19 @@
 #![feature(async_await)]
 
-use smtp_server::{mailparse::ParsedMail, Config, Email, MailHandler};
+use smtp_server::{
+    mailparse::ParsedMail, Accepted, Config, Email, ListenerRole, MailHandler, Verdict,
+};
 
 #[runtime::main]
 async fn main() {
     env_logger::init();
     let config = Config::build("localhost")
+        .listen((std::net::Ipv4Addr::LOCALHOST, 2525), ListenerRole::Relay)
         // .with_tls_from_pfx("identity.pfx").expect("Could not load identity.pfx")
         .build();
 
-    if let Err(err) = smtp_server::spawn(config, Handler).await {
+    let (_handle, server) = smtp_server::spawn(config, Handler);
+    if let Err(err) = server.await {
         eprintln!("SMTP server crashed: {:?}", err);
     }
     eprintln!("Server stopping");
@@ -18,15 +22,18 @@ async fn main() {
 struct Handler;
 
 impl MailHandler for Handler {
-    fn handle_mail(&mut self, email: Email) -> bool {
+    fn handle_mail(&mut self, email: Email) -> Verdict {
         println!("Received email");
-        println!("FROM: {:?}", email.from);
+        match email.from {
+            Some(from) => println!("FROM: <{}>", from),
+            None => println!("FROM: <>"),
+        }
         for to in email.to {
-            println!("TO: {:?}", to);
+            println!("TO: <{}>", to.mailbox);
         }
 
         print_mail(&email.body, 2);
-        true
+        Ok(Accepted::default())
     }
 }
 
@@ -10,6 +10,23 @@ impl<'a> MessageParser<'a> {
             offset: 0,
         }
     }
+
+    /// Returns everything that has not been consumed yet, without trimming it
+    pub fn rest(&self) -> &'a str {
+        &self.original[self.offset..]
+    }
+
+    /// Consumes bytes as long as `f` returns true, and returns them
+    pub fn consume_while(&mut self, f: impl Fn(u8) -> bool) -> &'a str {
+        let length = self
+            .rest()
+            .bytes()
+            .position(|b| !f(b))
+            .unwrap_or_else(|| self.rest().len());
+        let start = self.offset;
+        self.offset += length;
+        &self.original[start..self.offset]
+    }
 }
 
 impl MessageParser<'_> {
@@ -38,4 +55,24 @@ impl MessageParser<'_> {
     pub fn remaining(&self) -> &str {
         &self.original[self.offset..].trim()
     }
+
+    pub fn peek(&self) -> Option<u8> {
+        self.original.as_bytes().get(self.offset).cloned()
+    }
+
+    /// Consumes the given byte if it is the next one, and returns true if it was
+    pub fn consume(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.offset += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn next_char(&mut self) -> Option<char> {
+        let c = self.original[self.offset..].chars().next()?;
+        self.offset += c.len_utf8();
+        Some(c)
+    }
 }
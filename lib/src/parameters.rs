@@ -0,0 +1,182 @@
+use crate::dsn::{decode_xtext, DsnReturn};
+
+/// The ESMTP parameters that follow the address of a `MAIL FROM` or `RCPT TO` command, e.g.
+/// `SIZE=12345`. Keywords are stored in upper case.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Parameters {
+    parameters: Vec<(String, Option<String>)>,
+}
+
+impl Parameters {
+    /// Parses space separated `keyword[=value]` pairs, as defined in RFC 5321 section 4.1.2
+    pub(crate) fn parse(s: &str) -> Result<Parameters, &'static str> {
+        let mut parameters = Vec::new();
+        for parameter in s.split_whitespace() {
+            let (keyword, value) = match parameter.find('=') {
+                Some(index) => (&parameter[..index], Some(&parameter[index + 1..])),
+                None => (parameter, None),
+            };
+            let is_valid_keyword = keyword
+                .bytes()
+                .next()
+                .map(|b| b.is_ascii_alphanumeric())
+                .unwrap_or(false)
+                && keyword
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-');
+            if !is_valid_keyword {
+                return Err("Invalid parameter keyword");
+            }
+            if let Some(value) = value {
+                if value.is_empty() || value.bytes().any(|b| b < 33 || b == b'=' || b > 126) {
+                    return Err("Invalid parameter value");
+                }
+            }
+            parameters.push((keyword.to_ascii_uppercase(), value.map(str::to_owned)));
+        }
+        Ok(Parameters { parameters })
+    }
+
+    pub fn contains(&self, keyword: &str) -> bool {
+        self.parameters
+            .iter()
+            .any(|(k, _)| k.eq_ignore_ascii_case(keyword))
+    }
+
+    /// Returns the value of the given keyword, if it was sent with a value
+    pub fn value(&self, keyword: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(keyword))
+            .and_then(|(_, v)| v.as_ref().map(String::as_str))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.parameters
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_ref().map(String::as_str)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parameters.is_empty()
+    }
+
+    /// The body type of the `BODY` parameter, which defaults to 7 bit
+    pub fn body_type(&self) -> BodyType {
+        self.value("BODY")
+            .and_then(BodyType::from_value)
+            .unwrap_or(BodyType::SevenBit)
+    }
+
+    /// The decoded `ENVID` parameter of RFC 3461
+    pub fn envelope_id(&self) -> Option<String> {
+        self.value("ENVID").and_then(decode_xtext)
+    }
+
+    /// The `RET` parameter of RFC 3461
+    pub fn dsn_return(&self) -> Option<DsnReturn> {
+        self.value("RET").and_then(DsnReturn::from_value)
+    }
+
+    /// Returns true if the client sent the `SMTPUTF8` parameter of RFC 6531
+    pub fn smtputf8(&self) -> bool {
+        self.contains("SMTPUTF8")
+    }
+}
+
+/// The body type that was declared with the `BODY` parameter of `MAIL FROM`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyType {
+    /// `BODY=7BIT`, or no `BODY` parameter at all
+    SevenBit,
+    /// `BODY=8BITMIME` of RFC 6152
+    EightBitMime,
+    /// `BODY=BINARYMIME` of RFC 3030. The message can only be sent with `BDAT`.
+    BinaryMime,
+}
+
+impl BodyType {
+    pub(crate) fn from_value(value: &str) -> Option<BodyType> {
+        if value.eq_ignore_ascii_case("7BIT") {
+            Some(BodyType::SevenBit)
+        } else if value.eq_ignore_ascii_case("8BITMIME") {
+            Some(BodyType::EightBitMime)
+        } else if value.eq_ignore_ascii_case("BINARYMIME") {
+            Some(BodyType::BinaryMime)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keywords_and_values() {
+        let parameters = Parameters::parse("size=1000  BODY=8BITMIME smtputf8").unwrap();
+        assert_eq!(
+            parameters.iter().collect::<Vec<_>>(),
+            vec![
+                ("SIZE", Some("1000")),
+                ("BODY", Some("8BITMIME")),
+                ("SMTPUTF8", None),
+            ]
+        );
+        assert_eq!(parameters.value("Size"), Some("1000"));
+        assert_eq!(parameters.value("SMTPUTF8"), None);
+        assert!(parameters.contains("smtputf8"));
+        assert!(parameters.smtputf8());
+        assert_eq!(parameters.body_type(), BodyType::EightBitMime);
+    }
+
+    #[test]
+    fn no_parameters() {
+        let parameters = Parameters::parse("").unwrap();
+        assert!(parameters.is_empty());
+        assert_eq!(parameters.body_type(), BodyType::SevenBit);
+        assert!(!parameters.smtputf8());
+    }
+
+    #[test]
+    fn invalid_keywords() {
+        assert_eq!(
+            Parameters::parse("-SIZE=1"),
+            Err("Invalid parameter keyword")
+        );
+        assert_eq!(Parameters::parse("=1"), Err("Invalid parameter keyword"));
+        assert_eq!(
+            Parameters::parse("SI_ZE=1"),
+            Err("Invalid parameter keyword")
+        );
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert_eq!(Parameters::parse("SIZE="), Err("Invalid parameter value"));
+        assert_eq!(
+            Parameters::parse("SIZE=1=2"),
+            Err("Invalid parameter value")
+        );
+        assert_eq!(Parameters::parse("ENVID=ä"), Err("Invalid parameter value"));
+    }
+
+    #[test]
+    fn dsn_parameters() {
+        let parameters = Parameters::parse("RET=hdrs ENVID=QQ314159+2Bx").unwrap();
+        assert_eq!(parameters.dsn_return(), Some(DsnReturn::Headers));
+        assert_eq!(parameters.envelope_id(), Some("QQ314159+x".to_owned()));
+        let parameters = Parameters::parse("RET=ALL ENVID=+2").unwrap();
+        assert_eq!(parameters.dsn_return(), None);
+        assert_eq!(parameters.envelope_id(), None);
+    }
+
+    #[test]
+    fn unknown_body_type_is_seven_bit() {
+        let parameters = Parameters::parse("BODY=9BIT").unwrap();
+        assert_eq!(parameters.body_type(), BodyType::SevenBit);
+        let parameters = Parameters::parse("BODY=binarymime").unwrap();
+        assert_eq!(parameters.body_type(), BodyType::BinaryMime);
+    }
+}
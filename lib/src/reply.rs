@@ -0,0 +1,70 @@
+use std::borrow::Cow;
+
+/// A reply to an SMTP command, as defined in RFC 5321 section 4.2
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    /// The enhanced status code of RFC 3463, e.g. `(5, 1, 1)` for `5.1.1`
+    pub enhanced: Option<(u8, u16, u16)>,
+    /// The text of the reply. Every entry is sent as a separate line.
+    pub lines: Vec<Cow<'static, str>>,
+}
+
+impl Reply {
+    pub fn new(
+        code: u16,
+        enhanced: impl Into<Option<(u8, u16, u16)>>,
+        text: impl Into<Cow<'static, str>>,
+    ) -> Reply {
+        Reply {
+            code,
+            enhanced: enhanced.into(),
+            lines: vec![text.into()],
+        }
+    }
+
+    pub fn multiline(
+        code: u16,
+        enhanced: impl Into<Option<(u8, u16, u16)>>,
+        lines: Vec<Cow<'static, str>>,
+    ) -> Reply {
+        assert!(!lines.is_empty(), "A reply needs at least one line");
+        Reply {
+            code,
+            enhanced: enhanced.into(),
+            lines,
+        }
+    }
+
+    /// Serializes the reply into the lines that are sent to the client, without the CRLF.
+    /// Every line but the last one has a `-` after the code, and every line repeats the
+    /// enhanced status code (RFC 2034 section 4).
+    ///
+    /// The fields are public, so a handler can build a reply without lines, or with a line
+    /// break in the text. The first is sent as one empty line, and line breaks are replaced
+    /// with spaces, so they can not inject extra replies.
+    pub fn to_lines(&self) -> Vec<String> {
+        let empty = [Cow::Borrowed("")];
+        let lines = if self.lines.is_empty() {
+            &empty[..]
+        } else {
+            &self.lines[..]
+        };
+        let last = lines.len() - 1;
+        lines
+            .iter()
+            .enumerate()
+            .map(|(index, text)| {
+                let separator = if index == last { ' ' } else { '-' };
+                let text = text.replace(|c: char| c == '\r' || c == '\n', " ");
+                match self.enhanced {
+                    Some((class, subject, detail)) => format!(
+                        "{}{}{}.{}.{} {}",
+                        self.code, separator, class, subject, detail, text
+                    ),
+                    None => format!("{}{}{}", self.code, separator, text),
+                }
+            })
+            .collect()
+    }
+}
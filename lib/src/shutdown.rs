@@ -0,0 +1,85 @@
+use futures::channel::oneshot;
+use futures::future::{self, Shared};
+use futures::{Future, FutureExt};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Controls a server that was started with `spawn`. Dropping every handle does not stop the
+/// server.
+#[derive(Clone)]
+pub struct ServerHandle {
+    sender: Arc<Mutex<Option<oneshot::Sender<Duration>>>>,
+    is_shutting_down: Arc<AtomicBool>,
+    local_addrs: Shared<oneshot::Receiver<Vec<SocketAddr>>>,
+}
+
+/// What the server watches to know when to shut down
+#[derive(Clone)]
+pub(crate) struct ShutdownSignal {
+    receiver: Shared<oneshot::Receiver<Duration>>,
+    is_shutting_down: Arc<AtomicBool>,
+}
+
+/// The last value is used to report the addresses the server listens on
+pub(crate) fn channel() -> (
+    ServerHandle,
+    ShutdownSignal,
+    oneshot::Sender<Vec<SocketAddr>>,
+) {
+    let (sender, receiver) = oneshot::channel();
+    let (addrs_sender, addrs_receiver) = oneshot::channel();
+    let is_shutting_down = Arc::new(AtomicBool::new(false));
+    let handle = ServerHandle {
+        sender: Arc::new(Mutex::new(Some(sender))),
+        is_shutting_down: is_shutting_down.clone(),
+        local_addrs: addrs_receiver.shared(),
+    };
+    let signal = ShutdownSignal {
+        receiver: receiver.shared(),
+        is_shutting_down,
+    };
+    (handle, signal, addrs_sender)
+}
+
+impl ServerHandle {
+    /// Stop accepting connections, and answer new commands with 421. Messages that are being
+    /// received and handled are finished first. The server future resolves when every session
+    /// ended, or after `grace`, whichever comes first. Connections that are still open after
+    /// `grace` are closed, but a handler that is busy with a request is not interrupted.
+    pub fn shutdown(&self, grace: Duration) {
+        self.is_shutting_down.store(true, Ordering::SeqCst);
+        let sender = match self.sender.lock() {
+            Ok(mut sender) => sender.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        if let Some(sender) = sender {
+            let _ = sender.send(grace);
+        }
+    }
+
+    /// Resolves with the addresses the server listens on once every listener is bound, e.g. to
+    /// find out which port was picked for port 0. Resolves with no addresses if the server
+    /// could not start.
+    pub fn local_addrs(&self) -> impl Future<Output = Vec<SocketAddr>> {
+        self.local_addrs
+            .clone()
+            .map(|result| result.unwrap_or_default())
+    }
+}
+
+impl ShutdownSignal {
+    pub(crate) fn is_shutting_down(&self) -> bool {
+        self.is_shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Resolves with the grace period once `ServerHandle::shutdown` is called
+    pub(crate) fn wait(&self) -> impl Future<Output = Duration> {
+        self.receiver.clone().then(|result| match result {
+            Ok(grace) => future::ready(grace).left_future(),
+            // Every handle was dropped, so the server runs forever
+            Err(_) => future::pending().right_future(),
+        })
+    }
+}
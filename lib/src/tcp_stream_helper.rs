@@ -1,17 +1,26 @@
+/// Queues a line on a `LineReader`. It is only sent once the reader is flushed, so the replies
+/// to pipelined commands go out together.
 #[macro_export]
 macro_rules! log_and_send {
     ($client:expr, $addr:expr, $msg:expr) => {
         let str = $msg;
         let addr: SocketAddr = $addr;
-        log::trace!("[{}] OUT: {}", addr, str);
-        $client.send(str.as_bytes().to_vec()).await?;
-        $client.send(b"\r\n".to_vec()).await?;
+        log::log!($client.log_level(), "[{}] OUT: {}", addr, str);
+        $client.queue_line(str.as_bytes());
     };
     ($client:expr, $addr:expr, $msg:expr $(, $arg:expr)*) => {
         let str: String = format!($msg $(, $arg)*);
         let addr: SocketAddr = $addr;
-        log::trace!("[{}] OUT: {}", addr, str);
-        $client.send(str.as_bytes().to_vec()).await?;
-        $client.send(b"\r\n".to_vec()).await?;
+        log::log!($client.log_level(), "[{}] OUT: {}", addr, str);
+        $client.queue_line(str.as_bytes());
     }
 }
+
+#[macro_export]
+macro_rules! send_reply {
+    ($client:expr, $addr:expr, $reply:expr) => {
+        for line in $reply.to_lines() {
+            log_and_send!($client, $addr, line);
+        }
+    };
+}
@@ -1,25 +1,21 @@
-
 //! Async TLS streams
 //!
-//! This library is an implementation of TLS streams using the most appropriate
+//! This module is an implementation of TLS streams using the most appropriate
 //! system library by default for negotiating the connection. That is, on
 //! Windows this library uses SChannel, on OSX it uses SecureTransport, and on
 //! other platforms it uses OpenSSL.
 //!
-//! Each TLS stream implements the `Read` and `Write` traits to interact and
-//! interoperate with the rest of the futures I/O ecosystem. Client connections
-//! initiated from this crate verify hostnames automatically and by default.
-//!
-//! This crate primarily exports this ability through two newtypes,
-//! `TlsConnector` and `TlsAcceptor`. These newtypes augment the
-//! functionality provided by the `native-tls` crate, on which this crate is
-//! built. Configuration of TLS parameters is still primarily done through the
-//! `native-tls` crate.
+//! `native-tls` only works on blocking `Read` and `Write` streams. To make it work
+//! with `AsyncRead` and `AsyncWrite`, the underlying stream is wrapped in an `AllowStd`,
+//! which turns `Poll::Pending` into `io::ErrorKind::WouldBlock` and back.
 
-use futures::{Future, Poll};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::ready;
 use native_tls::{Error, HandshakeError};
+use std::future::Future;
 use std::io::{self, Read, Write};
-use futures::io::{AsyncRead, AsyncWrite};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 /// A wrapper around an underlying raw stream which implements the TLS or SSL
 /// protocol.
@@ -28,16 +24,8 @@ use futures::io::{AsyncRead, AsyncWrite};
 /// and both the server and the client are ready for receiving and sending
 /// data. Bytes read from a `TlsStream` are decrypted from `S` and bytes written
 /// to a `TlsStream` are encrypted when passing through to `S`.
-#[derive(Debug)]
 pub struct TlsStream<S> {
-    inner: native_tls::TlsStream<S>,
-}
-
-/// A wrapper around a `native_tls::TlsConnector`, providing an async `connect`
-/// method.
-#[derive(Clone)]
-pub struct TlsConnector {
-    inner: native_tls::TlsConnector,
+    inner: native_tls::TlsStream<AllowStd<S>>,
 }
 
 /// A wrapper around a `native_tls::TlsAcceptor`, providing an async `accept`
@@ -47,89 +35,110 @@ pub struct TlsAcceptor {
     inner: native_tls::TlsAcceptor,
 }
 
-/// Future returned from `TlsConnector::connect` which will resolve
-/// once the connection handshake has finished.
-pub struct Connect<S> {
-    inner: MidHandshake<S>,
-}
-
 /// Future returned from `TlsAcceptor::accept` which will resolve
 /// once the accept handshake has finished.
 pub struct Accept<S> {
-    inner: MidHandshake<S>,
+    inner: Handshake<S>,
 }
 
-struct MidHandshake<S> {
-    inner: Option<Result<native_tls::TlsStream<S>, HandshakeError<S>>>,
+enum Handshake<S> {
+    Start(native_tls::TlsAcceptor, S),
+    Mid(native_tls::MidHandshakeTlsStream<AllowStd<S>>),
+    Done,
 }
 
-impl<S> TlsStream<S> {
-    /// Get access to the internal `native_tls::TlsStream` stream which also
-    /// transitively allows access to `S`.
-    pub fn get_ref(&self) -> &native_tls::TlsStream<S> {
-        &self.inner
-    }
+/// A blocking `Read` and `Write` view of an `AsyncRead` and `AsyncWrite` stream.
+///
+/// `context` points to the `Context` of the task that is currently polling the stream, and is
+/// only valid for the duration of that poll.
+struct AllowStd<S> {
+    inner: S,
+    context: *mut (),
+}
+
+// The raw context pointer is only dereferenced while the owning task is being polled
+unsafe impl<S: Send> Send for AllowStd<S> {}
+unsafe impl<S: Sync> Sync for AllowStd<S> {}
 
-    /// Get mutable access to the internal `native_tls::TlsStream` stream which
-    /// also transitively allows mutable access to `S`.
-    pub fn get_mut(&mut self) -> &mut native_tls::TlsStream<S> {
-        &mut self.inner
+impl<S: Unpin> AllowStd<S> {
+    fn with_context<F, R>(&mut self, f: F) -> io::Result<R>
+    where
+        F: FnOnce(&mut Context<'_>, Pin<&mut S>) -> Poll<io::Result<R>>,
+    {
+        assert!(!self.context.is_null(), "AllowStd polled outside of a task");
+        let cx = unsafe { &mut *(self.context as *mut Context<'_>) };
+        match f(cx, Pin::new(&mut self.inner)) {
+            Poll::Ready(r) => r,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
     }
 }
 
-impl<S: Read + Write> Read for TlsStream<S> {
+impl<S: AsyncRead + Unpin> Read for AllowStd<S> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.inner.read(buf)
+        self.with_context(|cx, stream| stream.poll_read(cx, buf))
     }
 }
 
-impl<S: Read + Write> Write for TlsStream<S> {
+impl<S: AsyncWrite + Unpin> Write for AllowStd<S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.inner.write(buf)
+        self.with_context(|cx, stream| stream.poll_write(cx, buf))
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.inner.flush()
+        self.with_context(|cx, stream| stream.poll_flush(cx))
     }
 }
 
-impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {}
-
-impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
-    /*fn shutdown(&mut self) -> Poll<(), io::Error> {
-        try_nb!(self.inner.shutdown());
-        self.inner.get_mut().shutdown()
-    }*/
+fn set_context<S>(stream: &mut AllowStd<S>, cx: Option<&mut Context<'_>>) {
+    stream.context = match cx {
+        Some(cx) => cx as *mut Context<'_> as *mut (),
+        None => std::ptr::null_mut(),
+    };
 }
 
-impl TlsConnector {
-    /// Connects the provided stream with this connector, assuming the provided
-    /// domain.
-    ///
-    /// This function will internally call `TlsConnector::connect` to connect
-    /// the stream and returns a future representing the resolution of the
-    /// connection operation. The returned future will resolve to either
-    /// `TlsStream<S>` or `Error` depending if it's successful or not.
-    ///
-    /// This is typically used for clients who have already established, for
-    /// example, a TCP connection to a remote server. That stream is then
-    /// provided here to perform the client half of a connection to a
-    /// TLS-powered server.
-    pub fn connect<S>(&self, domain: &str, stream: S) -> Connect<S>
+impl<S: AsyncRead + AsyncWrite + Unpin> TlsStream<S> {
+    fn with_context<F, R>(&mut self, cx: &mut Context<'_>, f: F) -> Poll<io::Result<R>>
     where
-        S: AsyncRead + AsyncWrite,
+        F: FnOnce(&mut native_tls::TlsStream<AllowStd<S>>) -> io::Result<R>,
     {
-        Connect {
-            inner: MidHandshake {
-                inner: Some(self.inner.connect(domain, stream)),
-            },
+        set_context(self.inner.get_mut(), Some(cx));
+        let result = f(&mut self.inner);
+        set_context(self.inner.get_mut(), None);
+        match result {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            r => Poll::Ready(r),
         }
     }
 }
 
-impl From<native_tls::TlsConnector> for TlsConnector {
-    fn from(inner: native_tls::TlsConnector) -> TlsConnector {
-        TlsConnector { inner }
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().with_context(cx, |s| s.read(buf))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().with_context(cx, |s| s.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().with_context(cx, |s| s.flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.with_context(cx, |s| s.shutdown()))?;
+        Pin::new(&mut this.inner.get_mut().inner).poll_close(cx)
     }
 }
 
@@ -142,16 +151,15 @@ impl TlsAcceptor {
     /// `TlsStream<S>` or `Error` depending if it's successful or not.
     ///
     /// This is typically used after a new socket has been accepted from a
-    /// `TcpListener`. That socket is then passed to this function to perform
-    /// the server half of accepting a client connection.
+    /// `TcpListener`, or after a client sent `STARTTLS`. That socket is then
+    /// passed to this function to perform the server half of accepting a
+    /// client connection.
     pub fn accept<S>(&self, stream: S) -> Accept<S>
     where
-        S: AsyncRead + AsyncWrite,
+        S: AsyncRead + AsyncWrite + Unpin,
     {
         Accept {
-            inner: MidHandshake {
-                inner: Some(self.inner.accept(stream)),
-            },
+            inner: Handshake::Start(self.inner.clone(), stream),
         }
     }
 }
@@ -162,40 +170,39 @@ impl From<native_tls::TlsAcceptor> for TlsAcceptor {
     }
 }
 
-impl<S: AsyncRead + AsyncWrite> Future for Connect<S> {
-    type Item = TlsStream<S>;
-    type Error = Error;
-
-    fn poll(&mut self) -> Poll<TlsStream<S>, Error> {
-        self.inner.poll()
-    }
-}
-
-impl<S: AsyncRead + AsyncWrite> Future for Accept<S> {
-    type Item = TlsStream<S>;
-    type Error = Error;
-
-    fn poll(&mut self) -> Poll<TlsStream<S>, Error> {
-        self.inner.poll()
+impl std::fmt::Debug for TlsAcceptor {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("TlsAcceptor").finish()
     }
 }
 
-impl<S: AsyncRead + AsyncWrite> Future for MidHandshake<S> {
-    type Item = TlsStream<S>;
-    type Error = Error;
-
-    fn poll(&mut self) -> Poll<TlsStream<S>, Error> {
-        match self.inner.take().expect("cannot poll MidHandshake twice") {
-            Ok(stream) => Ok(TlsStream { inner: stream }.into()),
-            Err(HandshakeError::Failure(e)) => Err(e),
-            Err(HandshakeError::WouldBlock(s)) => match s.handshake() {
-                Ok(stream) => Ok(TlsStream { inner: stream }.into()),
-                Err(HandshakeError::Failure(e)) => Err(e),
-                Err(HandshakeError::WouldBlock(s)) => {
-                    self.inner = Some(Err(HandshakeError::WouldBlock(s)));
-                    Ok(Async::NotReady)
-                }
-            },
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for Accept<S> {
+    type Output = Result<TlsStream<S>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let result = match std::mem::replace(&mut this.inner, Handshake::Done) {
+            Handshake::Start(acceptor, stream) => acceptor.accept(AllowStd {
+                inner: stream,
+                context: cx as *mut Context<'_> as *mut (),
+            }),
+            Handshake::Mid(mut stream) => {
+                set_context(stream.get_mut(), Some(cx));
+                stream.handshake()
+            }
+            Handshake::Done => panic!("cannot poll Accept after it has completed"),
+        };
+        match result {
+            Ok(mut stream) => {
+                set_context(stream.get_mut(), None);
+                Poll::Ready(Ok(TlsStream { inner: stream }))
+            }
+            Err(HandshakeError::Failure(e)) => Poll::Ready(Err(e)),
+            Err(HandshakeError::WouldBlock(mut stream)) => {
+                set_context(stream.get_mut(), None);
+                this.inner = Handshake::Mid(stream);
+                Poll::Pending
+            }
         }
     }
 }
@@ -0,0 +1,96 @@
+use crate::reply::Reply;
+
+/// What `MailHandler::handle_mail` decided about a message
+pub type Verdict = Result<Accepted, Rejection>;
+
+/// The handler took responsibility for the message
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Accepted {
+    /// An id for the message, which is included in the 250 reply so it shows up in the logs of
+    /// the client
+    pub queue_id: Option<String>,
+}
+
+/// The handler refused the message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    pub code: u16,
+    /// The enhanced status code of RFC 3463, e.g. `(4, 3, 0)`
+    pub enhanced: Option<(u8, u16, u16)>,
+    pub message: String,
+    /// A permanent rejection makes the client bounce the message, a temporary one makes it
+    /// try again later
+    pub permanent: bool,
+}
+
+impl Accepted {
+    pub(crate) fn reply(&self) -> Reply {
+        match &self.queue_id {
+            Some(queue_id) => Reply::new(250, (2, 0, 0), format!("Ok: queued as {}", queue_id)),
+            None => Reply::new(250, (2, 0, 0), "Ok: Message received, over"),
+        }
+    }
+}
+
+impl Rejection {
+    /// 451, e.g. because a database is down. The client keeps the message and retries.
+    pub fn temporary(message: impl Into<String>) -> Rejection {
+        Rejection {
+            code: 451,
+            enhanced: Some((4, 3, 0)),
+            message: message.into(),
+            permanent: false,
+        }
+    }
+
+    /// 554, the message will never be accepted. The client bounces it.
+    pub fn permanent(message: impl Into<String>) -> Rejection {
+        Rejection {
+            code: 554,
+            enhanced: Some((5, 6, 0)),
+            message: message.into(),
+            permanent: true,
+        }
+    }
+
+    /// Falls back to 451 or 554 if `code` does not match `permanent`
+    pub(crate) fn reply(&self) -> Reply {
+        let code = match (self.permanent, self.code) {
+            (false, 400..=499) | (true, 500..=599) => self.code,
+            (false, _) => 451,
+            (true, _) => 554,
+        };
+        let enhanced = self
+            .enhanced
+            .filter(|(class, _, _)| u16::from(*class) == code / 100);
+        Reply::new(code, enhanced, self.message.clone())
+    }
+}
+
+pub(crate) fn reply(verdict: &Verdict) -> Reply {
+    match verdict {
+        Ok(accepted) => accepted.reply(),
+        Err(rejection) => rejection.reply(),
+    }
+}
+
+/// SMTP has only one reply for the whole message. It is accepted if any recipient accepted it,
+/// and the handler has to bounce it for the others. Otherwise a temporary rejection wins, so
+/// the client tries again.
+pub(crate) fn combine(verdicts: Vec<Verdict>) -> Verdict {
+    let mut result: Option<Rejection> = None;
+    for verdict in verdicts {
+        match verdict {
+            Ok(accepted) => return Ok(accepted),
+            Err(rejection) => {
+                if result
+                    .as_ref()
+                    .map_or(true, |current| current.permanent && !rejection.permanent)
+                {
+                    result = Some(rejection);
+                }
+            }
+        }
+    }
+    Err(result.unwrap_or_else(|| Rejection::temporary("No recipient accepted the message")))
+}
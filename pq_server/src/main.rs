@@ -2,7 +2,7 @@
 
 use fallible_iterator::FallibleIterator;
 use postgres::{Client, NoTls, Transaction};
-use smtp_server::{Config, Email, MailHandler};
+use smtp_server::{Accepted, Config, Email, MailHandler, Rejection, Verdict};
 use std::fmt::Write;
 use uuid::Uuid;
 
@@ -12,20 +12,29 @@ fn get_env(name: &str) -> String {
         Err(e) => panic!("Could not find environment variable {:?}: {:?}", name, e),
     }
 }
+
+const HANDLER_COUNT: usize = 4;
+
 #[runtime::main]
 async fn main() {
     let _ = dotenv::dotenv();
     let connection_string = get_env("DATABASE_URL");
-    let mut client =
-        Client::connect(&connection_string, NoTls).expect("Could not connect to server");
-    ensure_table_exists(&mut client);
+    // Every handler has its own connection, so messages are stored concurrently
+    let mut handlers: Vec<Handler> = (0..HANDLER_COUNT)
+        .map(|_| Handler {
+            client: Client::connect(&connection_string, NoTls)
+                .expect("Could not connect to server"),
+        })
+        .collect();
+    ensure_table_exists(&mut handlers[0].client);
 
     env_logger::init();
     let config = Config::build("localhost")
         // .with_tls_from_pfx("identity.pfx").expect("Could not load identity.pfx")
         .build();
 
-    let result = smtp_server::spawn(config, Handler { client }).await;
+    let (_handle, server) = smtp_server::spawn_pool(config, handlers);
+    let result = server.await;
 
     if let Err(e) = result {
         eprintln!("Server error: {:?}", e);
@@ -44,12 +53,18 @@ fn insert_mail(transaction: &mut Transaction, email: &Email) -> Result<Uuid, fai
 VALUES
     ($1, $2, $3)
 RETURNING id"#;
+    // The null reverse-path of bounces is stored as an empty string
+    let from = email
+        .from
+        .as_ref()
+        .map(ToString::to_string)
+        .unwrap_or_default();
     let mut result = transaction.query_iter(
         QUERY,
         &[
             &email.peer_addr.to_string().as_str(),
             &email.used_ssl,
-            &email.from.as_str(),
+            &from.as_str(),
         ],
     )?;
     let row = result
@@ -110,36 +125,42 @@ VALUES
     Ok(())
 }
 
-fn try_save_email(transaction: &mut Transaction, email: Email) -> Result<(), failure::Error> {
+fn try_save_email(transaction: &mut Transaction, email: Email) -> Result<Uuid, failure::Error> {
     let id = insert_mail(transaction, &email)?;
     for to in &email.to {
-        insert_mail_to(transaction, id, to)?;
+        insert_mail_to(transaction, id, &to.mailbox.to_string())?;
     }
     insert_mail_part(transaction, id, None, &email.body)?;
     println!("Inserted mail {:?}", id);
-    Ok(())
+    Ok(id)
 }
 
-fn try_run_transaction(client: &mut Client, email: Email) -> Result<bool, failure::Error> {
+fn try_run_transaction(client: &mut Client, email: Email) -> Result<Uuid, failure::Error> {
     let mut transaction = client.transaction()?;
-    if let Err(e) = try_save_email(&mut transaction, email) {
-        eprintln!("Could not save email: {:?}", e);
-        eprintln!("Email is LOST");
-        transaction.rollback()?;
-        Ok(false)
-    } else {
-        transaction.commit()?;
-        Ok(true)
+    match try_save_email(&mut transaction, email) {
+        Ok(id) => {
+            transaction.commit()?;
+            Ok(id)
+        }
+        Err(e) => {
+            transaction.rollback()?;
+            Err(e)
+        }
     }
 }
 
 impl MailHandler for Handler {
-    fn handle_mail(&mut self, email: Email) -> bool {
+    fn handle_mail(&mut self, email: Email) -> Verdict {
         match try_run_transaction(&mut self.client, email) {
-            Ok(v) => v,
+            Ok(id) => Ok(Accepted {
+                queue_id: Some(id.to_string()),
+            }),
             Err(e) => {
-                eprintln!("Could not handle DB transaction: {:?}", e);
-                false
+                eprintln!("Could not save email: {:?}", e);
+                // The client keeps the message and tries again later
+                Err(Rejection::temporary(
+                    "Could not store the message, try again later",
+                ))
             }
         }
     }